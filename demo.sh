@@ -8,26 +8,37 @@ ep="http://$host/api/v1"
 in_file='test.png'
 out_file='result.stl'
 
+token="$(curl \
+    -s \
+    --fail \
+    -X POST \
+    "$ep/create/new" \
+    | sed -E 's/.*"token":"([^"]+)".*/\1/')"
+auth="Authorization: Bearer $token"
+
 curl \
     -v \
     --fail-early \
-    -b cookies.txt -c cookies.txt \
-    -X POST \
-    "$ep/create/new" \
-    --next \
-    -b cookies.txt -c cookies.txt \
+    -H "$auth" \
     -F "=@$in_file" \
     "$ep/create/upload" \
     --next \
-    -b cookies.txt -c cookies.txt \
+    -H "$auth" \
     -X POST \
     "$ep/create/start"
 
-sleep 30
+until curl \
+    -s \
+    -H "$auth" \
+    "$ep/create/status" \
+    | grep -E '"state":"(done|failed|expired)"'
+do
+    sleep 2
+done
 
 curl \
     -v \
-    -b cookies.txt -c cookies.txt \
+    -H "$auth" \
     --output "$out_file" \
     -H "Accept: model/stl" \
     "$ep/create/result?type=model" \
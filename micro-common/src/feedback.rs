@@ -0,0 +1,111 @@
+use std::{fmt, str::FromStr};
+
+use rand::Rng;
+
+use crate::request_id::{RequestId, REDIS_NAMESPACE};
+
+const FEEDBACK_ID_BYTES: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseFeedbackIdError {
+    LengthError(usize),
+    InvalidDigit,
+}
+
+impl fmt::Display for ParseFeedbackIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseFeedbackIdError::LengthError(sz) => {
+                write!(
+                    f,
+                    "invalid length {}, expected {} hex digits",
+                    sz,
+                    2 * FEEDBACK_ID_BYTES
+                )
+            }
+            ParseFeedbackIdError::InvalidDigit => write!(f, "invalid hex digit"),
+        }
+    }
+}
+
+/// Identifier of published feedback on a dataset image
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FeedbackId([u8; FEEDBACK_ID_BYTES]);
+
+/// Identifier of an image attached to feedback, unique across all feedback
+pub type FeedbackImageId = FeedbackId;
+
+impl FeedbackId {
+    pub fn new() -> Self {
+        FeedbackId(rand::thread_rng().gen())
+    }
+}
+
+impl Default for FeedbackId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for FeedbackId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FeedbackId {
+    type Err = ParseFeedbackIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 2 * FEEDBACK_ID_BYTES {
+            return Err(ParseFeedbackIdError::LengthError(s.len()));
+        }
+        let mut bytes = [0u8; FEEDBACK_ID_BYTES];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            let digits = s
+                .get(2 * i..2 * i + 2)
+                .ok_or(ParseFeedbackIdError::InvalidDigit)?;
+            *b = u8::from_str_radix(digits, 16).map_err(|_| ParseFeedbackIdError::InvalidDigit)?;
+        }
+        Ok(FeedbackId(bytes))
+    }
+}
+
+/// Hash holding feedback that is being submitted (metadata and uploaded images)
+pub fn feedback_request_key(request_id: RequestId) -> String {
+    const REDIS_NAMESPACE_FEEDBACK_REQUEST: &str = "feedback_request";
+    format!(
+        "{}:{}:{}",
+        REDIS_NAMESPACE, REDIS_NAMESPACE_FEEDBACK_REQUEST, request_id
+    )
+}
+
+/// Hash holding published feedback
+pub fn feedback_key(feedback_id: FeedbackId) -> String {
+    const REDIS_NAMESPACE_FEEDBACK: &str = "feedback";
+    format!(
+        "{}:{}:{}",
+        REDIS_NAMESPACE, REDIS_NAMESPACE_FEEDBACK, feedback_id
+    )
+}
+
+/// Hash holding an image of published feedback
+pub fn feedback_image_key(image_id: FeedbackImageId) -> String {
+    const REDIS_NAMESPACE_FEEDBACK_IMAGE: &str = "feedback_image";
+    format!(
+        "{}:{}:{}",
+        REDIS_NAMESPACE, REDIS_NAMESPACE_FEEDBACK_IMAGE, image_id
+    )
+}
+
+/// Sorted set of the published feedback on a dataset image, scored by creation time
+pub fn feedback_index_key(dataset_name: &str, image_id: &str) -> String {
+    const REDIS_NAMESPACE_FEEDBACK_INDEX: &str = "feedback_index";
+    format!(
+        "{}:{}:{}:{}",
+        REDIS_NAMESPACE, REDIS_NAMESPACE_FEEDBACK_INDEX, dataset_name, image_id
+    )
+}
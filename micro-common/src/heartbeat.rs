@@ -0,0 +1,23 @@
+use crate::request_id::REDIS_NAMESPACE;
+
+/// Interval between heartbeats of a worker
+pub const WORKER_HEARTBEAT_INTERVAL_SECONDS: u64 = 5;
+/// Workers without a heartbeat for this long are considered dead
+pub const WORKER_HEARTBEAT_TTL_SECONDS: u64 = 30;
+
+/// Hash holding the heartbeat of a single worker (consumer)
+pub fn worker_key(consumer_name: &str) -> String {
+    const REDIS_NAMESPACE_WORKER: &str = "worker";
+    format!(
+        "{}:{}:{}",
+        REDIS_NAMESPACE, REDIS_NAMESPACE_WORKER, consumer_name
+    )
+}
+
+/// Set of the consumer names of all workers that published a heartbeat
+///
+/// Entries of dead workers are removed lazily by readers.
+pub fn worker_registry_key() -> String {
+    const REDIS_NAMESPACE_WORKERS: &str = "workers";
+    format!("{}:{}", REDIS_NAMESPACE, REDIS_NAMESPACE_WORKERS)
+}
@@ -1,8 +1,23 @@
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use serde::{Deserialize, Serialize};
 
 pub mod request_id;
 pub use request_id::*;
 
+pub mod upload_id;
+pub use upload_id::*;
+
+pub mod heartbeat;
+pub use heartbeat::*;
+
+pub mod feedback;
+pub use feedback::*;
+
 pub mod settings;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -11,6 +26,15 @@ pub enum ImageType {
     JPEG,
 }
 
+impl ImageType {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageType::PNG => "image/png",
+            ImageType::JPEG => "image/jpeg",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StoredImage {
     pub image_type: ImageType,
@@ -23,7 +47,30 @@ pub struct ImageRequest {
     pub image: StoredImage,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Maximum number of points of a polygon mask
+pub const MAX_POLYGON_POINTS: usize = 256;
+
+/// Shape of the mask that cuts the seal out of the selected region
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MaskShape {
+    /// Ellipse touching the borders of the region
+    #[default]
+    Ellipse,
+    /// Largest circle centered in the region
+    Circle,
+    /// The whole region
+    Rectangle,
+    /// The whole region, with corners rounded by `radius` pixels
+    RoundedRectangle { radius: i32 },
+    /// Arbitrary polygon, with points given in pixels of the uploaded image
+    Polygon { points: Vec<(i32, i32)> },
+}
+
+/// User settings for a create request
+///
+/// The selected region (`start_*` inclusive, `end_*` exclusive) is given in pixels of the uploaded image.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct RequestSettings {
     pub start_x: i32,
     pub end_x: i32,
@@ -31,7 +78,247 @@ pub struct RequestSettings {
     pub end_y: i32,
     pub is_inverted: bool,
     pub is_low_quality: bool,
+    #[serde(default)]
+    pub mask: MaskShape,
+}
+
+impl RequestSettings {
+    /// Default settings, selecting the whole image
+    pub fn full_image(width: i32, height: i32) -> Self {
+        Self {
+            start_x: 0,
+            end_x: width,
+            start_y: 0,
+            end_y: height,
+            is_inverted: false,
+            is_low_quality: false,
+            mask: MaskShape::default(),
+        }
+    }
+
+    /// Checks that the selected region is non-empty and lies within an image of the given size
+    pub fn validate(&self, width: i32, height: i32) -> Result<(), String> {
+        if self.start_x < 0 || self.start_y < 0 {
+            return Err("region starts outside of the image".to_string());
+        }
+        if self.end_x > width || self.end_y > height {
+            return Err(format!(
+                "region ends outside of the image (size {}x{})",
+                width, height
+            ));
+        }
+        if self.start_x >= self.end_x || self.start_y >= self.end_y {
+            return Err("region is empty".to_string());
+        }
+        match &self.mask {
+            MaskShape::RoundedRectangle { radius } if *radius < 0 => {
+                return Err("corner radius must not be negative".to_string());
+            }
+            MaskShape::Polygon { points } => {
+                if points.len() < 3 {
+                    return Err("polygon needs at least 3 points".to_string());
+                }
+                if points.len() > MAX_POLYGON_POINTS {
+                    return Err(format!(
+                        "polygon has more than {} points",
+                        MAX_POLYGON_POINTS
+                    ));
+                }
+                let outside = points.iter().any(|&(x, y)| {
+                    x < self.start_x || x >= self.end_x || y < self.start_y || y >= self.end_y
+                });
+                if outside {
+                    return Err("polygon points must lie within the region".to_string());
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Whether both settings select the same part of the image (ignoring the model options)
+    pub fn same_selection(&self, other: &RequestSettings) -> bool {
+        (self.start_x, self.end_x, self.start_y, self.end_y)
+            == (other.start_x, other.end_x, other.start_y, other.end_y)
+            && self.mask == other.mask
+    }
+}
+
+/// Lifecycle of a submitted job, stored in the `state` field of `status_key`
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Processing,
+    Done,
+    Failed,
+    Expired,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Processing => "processing",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+            JobState::Expired => "expired",
+        }
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for JobState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(JobState::Queued),
+            "processing" => Ok(JobState::Processing),
+            "done" => Ok(JobState::Done),
+            "failed" => Ok(JobState::Failed),
+            "expired" => Ok(JobState::Expired),
+            _ => Err(format!("unknown job state \u{201C}{}\u{201D}", s)),
+        }
+    }
 }
 
 pub const FABSEAL_SUBMISSION_QUEUE: &str = "fs_submission";
 pub const FABSEAL_SUBMISSION_CONSUMER_GROUP: &str = "fs_submission_group";
+/// Jobs that failed permanently, kept for inspection by operators
+pub const FABSEAL_SUBMISSION_DEAD_LETTER_QUEUE: &str = "fs_submission_dead";
+
+/// Seconds since the Unix epoch, as stored in the timestamp fields in Redis
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start_x: i32, end_x: i32, start_y: i32, end_y: i32) -> RequestSettings {
+        RequestSettings {
+            start_x,
+            end_x,
+            start_y,
+            end_y,
+            ..RequestSettings::full_image(0, 0)
+        }
+    }
+
+    #[test]
+    fn accepts_region_within_image() {
+        assert_eq!(
+            RequestSettings::full_image(640, 480).validate(640, 480),
+            Ok(())
+        );
+        assert_eq!(region(10, 11, 20, 21).validate(640, 480), Ok(()));
+    }
+
+    #[test]
+    fn rejects_region_outside_image() {
+        for settings in &[
+            region(-1, 100, 0, 100),
+            region(0, 100, -1, 100),
+            region(0, 641, 0, 100),
+            region(0, 100, 0, 481),
+        ] {
+            assert!(settings.validate(640, 480).is_err(), "{:?}", settings);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_region() {
+        for settings in &[
+            region(10, 10, 0, 100),
+            region(0, 100, 10, 10),
+            region(20, 10, 0, 100),
+            region(0, 100, 20, 10),
+        ] {
+            assert!(settings.validate(640, 480).is_err(), "{:?}", settings);
+        }
+    }
+
+    fn polygon(points: Vec<(i32, i32)>) -> RequestSettings {
+        RequestSettings {
+            mask: MaskShape::Polygon { points },
+            ..region(10, 110, 20, 120)
+        }
+    }
+
+    #[test]
+    fn accepts_polygon_within_region() {
+        let settings = polygon(vec![(10, 20), (109, 20), (109, 119)]);
+        assert_eq!(settings.validate(640, 480), Ok(()));
+    }
+
+    #[test]
+    fn rejects_polygon_with_too_few_points() {
+        assert!(polygon(vec![]).validate(640, 480).is_err());
+        assert!(polygon(vec![(10, 20), (50, 50)])
+            .validate(640, 480)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_polygon_points_outside_region() {
+        for point in &[(9, 50), (110, 50), (50, 19), (50, 120)] {
+            let settings = polygon(vec![(10, 20), (50, 50), *point]);
+            assert!(settings.validate(640, 480).is_err(), "{:?}", point);
+        }
+    }
+
+    #[test]
+    fn limits_polygon_points() {
+        let points = |n: usize| (0..n).map(|i| (10 + (i % 100) as i32, 20)).collect();
+        assert_eq!(
+            polygon(points(MAX_POLYGON_POINTS)).validate(640, 480),
+            Ok(())
+        );
+        assert!(polygon(points(MAX_POLYGON_POINTS + 1))
+            .validate(640, 480)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_negative_corner_radius() {
+        let rounded = |radius| RequestSettings {
+            mask: MaskShape::RoundedRectangle { radius },
+            ..region(10, 110, 20, 120)
+        };
+        assert_eq!(rounded(0).validate(640, 480), Ok(()));
+        assert!(rounded(-1).validate(640, 480).is_err());
+    }
+
+    #[test]
+    fn same_selection_ignores_model_options() {
+        let settings = region(0, 100, 0, 100);
+        let other = RequestSettings {
+            is_inverted: true,
+            is_low_quality: true,
+            ..settings.clone()
+        };
+        assert!(settings.same_selection(&other));
+    }
+
+    #[test]
+    fn same_selection_compares_region_and_mask() {
+        let settings = region(0, 100, 0, 100);
+        assert!(!settings.same_selection(&region(1, 100, 0, 100)));
+        assert!(!settings.same_selection(&region(0, 100, 0, 99)));
+        let circle = RequestSettings {
+            mask: MaskShape::Circle,
+            ..settings.clone()
+        };
+        assert!(!settings.same_selection(&circle));
+    }
+}
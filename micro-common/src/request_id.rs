@@ -2,6 +2,7 @@ use std::{
     array::TryFromSliceError,
     convert::{TryFrom, TryInto},
     fmt,
+    str::FromStr,
 };
 
 use serde::{Deserialize, Serialize};
@@ -79,7 +80,49 @@ impl fmt::Display for RequestId {
     }
 }
 
-const REDIS_NAMESPACE: &str = "fsdata_v1";
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseRequestIdError {
+    LengthError(usize),
+    InvalidDigit,
+}
+
+impl fmt::Display for ParseRequestIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseRequestIdError::LengthError(sz) => {
+                write!(f, "invalid length {}, expected 8 hex digits", sz)
+            }
+            ParseRequestIdError::InvalidDigit => write!(f, "invalid hex digit"),
+        }
+    }
+}
+
+impl FromStr for RequestId {
+    type Err = ParseRequestIdError;
+
+    /// Parses the representation produced by `Display`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 8 {
+            return Err(ParseRequestIdError::LengthError(s.len()));
+        }
+        if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseRequestIdError::InvalidDigit);
+        }
+        u32::from_str_radix(s, 16)
+            .map(RequestId)
+            .map_err(|_| ParseRequestIdError::InvalidDigit)
+    }
+}
+
+pub(crate) const REDIS_NAMESPACE: &str = "fsdata_v1";
+
+pub fn request_key(request_id: RequestId) -> String {
+    const REDIS_NAMESPACE_REQUEST: &str = "request";
+    format!(
+        "{}:{}:{}",
+        REDIS_NAMESPACE, REDIS_NAMESPACE_REQUEST, request_id
+    )
+}
 
 pub fn result_key(request_id: RequestId) -> String {
     const REDIS_NAMESPACE_RESULT: &str = "result";
@@ -89,6 +132,45 @@ pub fn result_key(request_id: RequestId) -> String {
     )
 }
 
+pub fn status_key(request_id: RequestId) -> String {
+    const REDIS_NAMESPACE_STATUS: &str = "status";
+    format!(
+        "{}:{}:{}",
+        REDIS_NAMESPACE, REDIS_NAMESPACE_STATUS, request_id
+    )
+}
+
+pub fn log_key(request_id: RequestId) -> String {
+    const REDIS_NAMESPACE_LOG: &str = "log";
+    format!("{}:{}:{}", REDIS_NAMESPACE, REDIS_NAMESPACE_LOG, request_id)
+}
+
+/// Token bucket of a client (`scope` is e.g. "ip:<address>") for a route
+pub fn rate_limit_key(route: &str, scope: &str) -> String {
+    const REDIS_NAMESPACE_RATE_LIMIT: &str = "ratelimit";
+    format!(
+        "{}:{}:{}:{}",
+        REDIS_NAMESPACE, REDIS_NAMESPACE_RATE_LIMIT, route, scope
+    )
+}
+
+/// Hash holding the state of a session stored in Redis (`session.backend = "redis"`)
+pub fn session_key(session_id: &str) -> String {
+    const REDIS_NAMESPACE_SESSION: &str = "session";
+    format!(
+        "{}:{}:{}",
+        REDIS_NAMESPACE, REDIS_NAMESPACE_SESSION, session_id
+    )
+}
+
+/// Sorted set of the ids of all sessions stored in Redis, scored by their expiry time
+///
+/// Entries of expired sessions are removed lazily by readers.
+pub fn session_registry_key() -> String {
+    const REDIS_NAMESPACE_SESSIONS: &str = "sessions";
+    format!("{}:{}", REDIS_NAMESPACE, REDIS_NAMESPACE_SESSIONS)
+}
+
 pub fn input_key(request_id: RequestId) -> String {
     const REDIS_NAMESPACE_INPUT: &str = "input";
     format!(
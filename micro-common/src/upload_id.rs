@@ -0,0 +1,142 @@
+use std::{fmt, str::FromStr};
+
+use rand::Rng;
+
+use crate::request_id::REDIS_NAMESPACE;
+
+const UPLOAD_ID_BYTES: usize = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseUploadIdError {
+    LengthError(usize),
+    InvalidDigit,
+}
+
+impl fmt::Display for ParseUploadIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseUploadIdError::LengthError(sz) => {
+                write!(
+                    f,
+                    "invalid length {}, expected {} hex digits",
+                    sz,
+                    2 * UPLOAD_ID_BYTES
+                )
+            }
+            ParseUploadIdError::InvalidDigit => write!(f, "invalid hex digit"),
+        }
+    }
+}
+
+/// Identifier of a finished, publicly shared creation
+///
+/// Unlike `RequestId`, this is shared via public links, so it is long enough not to be guessable.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UploadId([u8; UPLOAD_ID_BYTES]);
+
+impl UploadId {
+    pub fn new() -> Self {
+        UploadId(rand::thread_rng().gen())
+    }
+}
+
+impl Default for UploadId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for UploadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for UploadId {
+    type Err = ParseUploadIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 2 * UPLOAD_ID_BYTES {
+            return Err(ParseUploadIdError::LengthError(s.len()));
+        }
+        // from_str_radix accepts a leading sign
+        if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseUploadIdError::InvalidDigit);
+        }
+        let mut bytes = [0u8; UPLOAD_ID_BYTES];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            let digits = s
+                .get(2 * i..2 * i + 2)
+                .ok_or(ParseUploadIdError::InvalidDigit)?;
+            *b = u8::from_str_radix(digits, 16).map_err(|_| ParseUploadIdError::InvalidDigit)?;
+        }
+        Ok(UploadId(bytes))
+    }
+}
+
+pub fn public_upload_key(upload_id: UploadId) -> String {
+    const REDIS_NAMESPACE_PUBLIC_UPLOAD: &str = "public_upload";
+    format!(
+        "{}:{}:{}",
+        REDIS_NAMESPACE, REDIS_NAMESPACE_PUBLIC_UPLOAD, upload_id
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_displayed_id() {
+        let id = UploadId::new();
+        assert_eq!(id.to_string().parse(), Ok(id));
+        assert_eq!(
+            "00ff10a0b1c2d3e4f5A6B7C8D9EAFB0C"
+                .parse::<UploadId>()
+                .map(|id| id.to_string()),
+            Ok("00ff10a0b1c2d3e4f5a6b7c8d9eafb0c".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        assert_eq!(
+            "".parse::<UploadId>(),
+            Err(ParseUploadIdError::LengthError(0))
+        );
+        assert_eq!(
+            "00ff10a0b1c2d3e4f5a6b7c8d9eafb0".parse::<UploadId>(),
+            Err(ParseUploadIdError::LengthError(31))
+        );
+        assert_eq!(
+            "00ff10a0b1c2d3e4f5a6b7c8d9eafb0c0".parse::<UploadId>(),
+            Err(ParseUploadIdError::LengthError(33))
+        );
+    }
+
+    #[test]
+    fn rejects_non_hex_digits() {
+        for s in &[
+            "+0ff10a0b1c2d3e4f5a6b7c8d9eafb0c",
+            "00ff10a0b1c2d3e4f5a6b7c8d9eafb+c",
+            "-0ff10a0b1c2d3e4f5a6b7c8d9eafb0c",
+            "00ff10a0b1c2d3e4f5a6b7c8d9eafb0g",
+            " 0ff10a0b1c2d3e4f5a6b7c8d9eafb0c",
+        ] {
+            assert_eq!(
+                s.parse::<UploadId>(),
+                Err(ParseUploadIdError::InvalidDigit),
+                "{:?}",
+                s
+            );
+        }
+        // Multi-byte characters must not split a digit pair
+        assert_eq!(
+            "\u{e9}f10a0b1c2d3e4f5a6b7c8d9eafb0c0".parse::<UploadId>(),
+            Err(ParseUploadIdError::InvalidDigit)
+        );
+    }
+}
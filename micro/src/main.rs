@@ -1,33 +1,49 @@
-use actix_redis::RedisSession;
+use actix::Addr;
 use actix_session::CookieSession;
 use actix_web::{cookie::SameSite, http, web::Data};
 
 use actix_cors::Cors;
 
-use rand::Rng;
-
-use actix_web::{middleware::Logger, web, App, HttpServer};
-
-use actix_redis::RedisActor;
+use actix_web::{dev::Service, middleware::Logger, web, App, HttpServer};
 
 use log::debug;
 
+use futures_util::future::FutureExt;
+use std::time::Instant;
+
 mod site;
-use site::create::create_service;
+use site::{
+    admin::admin_service,
+    create::create_service,
+    feedback::{feedback_data_service, feedback_service},
+    health::health_service,
+    job_token::JobTokens,
+};
 
 mod settings;
 
-use settings::Settings;
+use settings::{SessionBackend, Settings};
 use time::Duration;
 
 mod prepare_image;
 
+mod metrics;
+
+mod rate_limit;
+use rate_limit::RateLimiter;
+
+mod redis_connection;
+use redis_connection::{RedisActor, RedisConnector};
+
+mod session;
+use session::{RedisSession, SessionKeyRotation, SessionKeys, SessionStore, SESSION_COOKIE_NAME};
+
 const COOKIE_DURATION: Duration = Duration::hour();
 
 fn create_cookie_session(settings: &Settings, key: &[u8]) -> CookieSession {
     let s = CookieSession::private(key)
         .max_age(COOKIE_DURATION.whole_seconds())
-        .name("fabseal_session")
+        .name(SESSION_COOKIE_NAME)
         .path("/api/v1/create")
         .http_only(true)
         .same_site(SameSite::Strict);
@@ -42,10 +58,10 @@ fn create_cookie_session(settings: &Settings, key: &[u8]) -> CookieSession {
     }
 }
 
-fn create_redis_session(settings: &Settings, key: &[u8]) -> RedisSession {
-    let s = RedisSession::new(settings.redis.address.clone(), key)
+fn create_redis_session(settings: &Settings, key: &[u8], redis: Addr<RedisActor>) -> RedisSession {
+    let s = RedisSession::new(redis, key)
         .ttl(settings.limits.session_ttl)
-        .cookie_name("fabseal_session")
+        .cookie_name(SESSION_COOKIE_NAME)
         .cookie_path("/api/v1/create")
         .cookie_http_only(true)
         .cookie_max_age(COOKIE_DURATION)
@@ -61,13 +77,26 @@ fn create_redis_session(settings: &Settings, key: &[u8]) -> RedisSession {
     }
 }
 
+fn create_session(settings: &Settings, key: &[u8], redis: Addr<RedisActor>) -> SessionStore {
+    match settings.session.backend {
+        SessionBackend::Cookie => SessionStore::Cookie(create_cookie_session(settings, key)),
+        SessionBackend::Redis => SessionStore::Redis(create_redis_session(settings, key, redis)),
+    }
+}
+
 fn build_cors(settings: &Settings) -> actix_cors::Cors {
     if settings.debug {
         Cors::permissive()
     } else {
         let mut cors = Cors::default()
             .allowed_methods(vec!["GET", "POST"])
-            .allowed_headers(vec![http::header::CONTENT_TYPE, http::header::ACCEPT])
+            .allowed_headers(vec![
+                http::header::CONTENT_TYPE,
+                http::header::ACCEPT,
+                http::header::AUTHORIZATION,
+            ])
+            // For 429 and 503 responses
+            .expose_headers(vec![http::header::RETRY_AFTER])
             .max_age(3600)
             .supports_credentials();
         for origin in &settings.http.cors_origins {
@@ -85,21 +114,56 @@ async fn main() -> std::io::Result<()> {
     let settings = Settings::new().unwrap();
     debug!("settings: {:?}", settings);
 
-    let key: [u8; 32] = rand::thread_rng().gen();
+    let redis_settings = settings.redis.resolve().map_err(std::io::Error::other)?;
+    let redis_connector = RedisConnector::new(redis_settings)?;
+
+    let session_keys = SessionKeys::load(&settings)?;
 
     let ep = settings.http.endpoint.clone();
     HttpServer::new(move || {
-        let redis = RedisActor::start(settings.redis.address.as_str());
+        let redis = RedisActor::start(redis_connector.clone());
         let cors = build_cors(&settings);
 
         App::new()
-            .app_data(Data::new(redis))
+            .app_data(Data::new(redis.clone()))
             .app_data(Data::new(settings.clone()))
+            .app_data(Data::new(JobTokens::new(&session_keys)))
             // .wrap(actix_web::middleware::Compress::default())
+            // The rate limiter needs the session, so it has to be wrapped by the session middleware
+            .wrap(RateLimiter)
             .wrap(cors)
             .wrap(Logger::default())
-            .wrap(create_cookie_session(&settings, &key))
-            .service(web::scope("/api/v1").configure(create_service))
+            .wrap_fn(|req, srv| {
+                let start = Instant::now();
+                let route = req
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string());
+                let method = req.method().to_string();
+                srv.call(req).map(move |res| {
+                    let status = match &res {
+                        Ok(res) => res.status(),
+                        Err(e) => e.as_response_error().status_code(),
+                    };
+                    metrics::observe_request(&route, &method, status, start.elapsed());
+                    res
+                })
+            })
+            .wrap_fn(|req, srv| {
+                session::renew_rotated_session(&req);
+                srv.call(req)
+            })
+            .wrap(create_session(&settings, &session_keys.current, redis))
+            // Lets the session middleware read cookies encrypted with previous keys
+            .wrap(SessionKeyRotation::new(session_keys.clone()))
+            .service(metrics::metrics)
+            .configure(health_service)
+            .configure(feedback_data_service)
+            .service(
+                web::scope("/api/v1")
+                    .configure(create_service)
+                    .configure(feedback_service)
+                    .configure(admin_service),
+            )
     })
     .bind(ep)?
     .run()
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use actix::Addr;
+use actix_web::{get, http::StatusCode, web, HttpResponse, Result as AWResult};
+
+use lazy_static::lazy_static;
+use log::error;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::{
+    redis_connection::RedisActor,
+    site::{
+        admin::Admin,
+        queue::{submission_group_info, undelivered_count},
+    },
+};
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "fabseal_http_requests_total",
+        "Number of HTTP requests by route, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "fabseal_http_request_duration_seconds",
+        "HTTP request latencies by route and method",
+        &["route", "method"]
+    )
+    .unwrap();
+    pub(crate) static ref UPLOAD_SIZE: Histogram = register_histogram!(
+        "fabseal_upload_size_bytes",
+        "Size of uploaded images",
+        exponential_buckets(16.0 * 1024.0, 2.0, 10).unwrap()
+    )
+    .unwrap();
+    pub(crate) static ref PREPARE_IMAGE_DURATION: Histogram = register_histogram!(
+        "fabseal_prepare_image_duration_seconds",
+        "Duration of preparing heightmaps from uploaded images"
+    )
+    .unwrap();
+    pub(crate) static ref PREPARE_IMAGE_FAILURES: IntCounter = register_int_counter!(
+        "fabseal_prepare_image_failures_total",
+        "Number of uploaded images that could not be prepared"
+    )
+    .unwrap();
+    pub(crate) static ref REDIS_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "fabseal_redis_errors_total",
+        "Number of failed Redis commands by command",
+        &["command"]
+    )
+    .unwrap();
+    pub(crate) static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "fabseal_rate_limited_requests_total",
+        "Number of requests rejected by the rate limiter by route",
+        &["route"]
+    )
+    .unwrap();
+    pub(crate) static ref SESSIONS_CREATED: IntCounter = register_int_counter!(
+        "fabseal_sessions_created_total",
+        "Number of create requests started with /create/new"
+    )
+    .unwrap();
+    static ref QUEUE_LENGTH: IntGauge = register_int_gauge!(
+        "fabseal_submission_queue_length",
+        "Number of jobs waiting for or being processed by a worker"
+    )
+    .unwrap();
+    static ref QUEUE_PENDING: IntGauge = register_int_gauge!(
+        "fabseal_submission_queue_pending",
+        "Number of jobs delivered to workers, but not acknowledged yet"
+    )
+    .unwrap();
+    static ref QUEUE_CONSUMERS: IntGauge = register_int_gauge!(
+        "fabseal_submission_queue_consumers",
+        "Number of consumers in the submission consumer group"
+    )
+    .unwrap();
+}
+
+/// Records a finished HTTP request, `route` is the matched pattern (not the path, to keep the number of labels bounded)
+pub(crate) fn observe_request(route: &str, method: &str, status: StatusCode, duration: Duration) {
+    HTTP_REQUESTS
+        .with_label_values(&[route, method, status.as_str()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[route, method])
+        .observe(duration.as_secs_f64());
+}
+
+async fn update_queue_metrics(redis: &Addr<RedisActor>) -> AWResult<()> {
+    let info = submission_group_info(redis).await?;
+    let undelivered = undelivered_count(redis, info.as_ref()).await?;
+
+    let (pending, consumers) = info.map_or((0, 0), |info| (info.pending, info.consumers));
+    // Acknowledged entries stay in the stream until trimmed, so XLEN would overcount
+    QUEUE_LENGTH.set(pending + undelivered);
+    QUEUE_PENDING.set(pending);
+    QUEUE_CONSUMERS.set(consumers);
+
+    Ok(())
+}
+
+/// Requires the admin token, like the admin API
+#[get("/metrics")]
+pub(crate) async fn metrics(
+    _admin: Admin,
+    redis: web::Data<Addr<RedisActor>>,
+) -> AWResult<HttpResponse> {
+    // Report the other metrics even if Redis is unavailable
+    if let Err(e) = update_queue_metrics(&redis).await {
+        error!("error while updating queue metrics: {}", e);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| {
+            error!("error while encoding metrics: {}", e);
+            actix_web::error::ErrorInternalServerError("Metrics error")
+        })?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer))
+}
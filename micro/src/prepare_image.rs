@@ -1,18 +1,21 @@
 use std::cmp::{max, min};
 
+use fabseal_micro_common::{MaskShape, RequestSettings};
+
 use opencv::{
-    core::{Mat, Point2f, Rect, Rect_, Scalar, Scalar_, Size, Vector, CV_8UC1, NORM_MINMAX},
+    core::{Mat, Point, Point2f, Rect, Rect_, Scalar, Scalar_, Size, Vector, CV_8UC1, NORM_MINMAX},
     prelude::*,
 };
 
 const BORDER_PIXELS: i32 = 2;
 const IMG_TYPE: i32 = CV_8UC1;
 
-fn get_mask_on(
-    image_size: Size,
+fn draw_ellipse(
+    mask: &mut Mat,
+    color: Scalar,
     topleft: Point2f,
     downright: Point2f,
-) -> opencv::Result<(Mat, Rect)> {
+) -> opencv::Result<Rect> {
     let center = Point2f {
         x: ((topleft.x + downright.x) / 2.0).round(),
         y: ((topleft.y + downright.y) / 2.0).round(),
@@ -39,21 +42,121 @@ fn get_mask_on(
     let pts: Vector<Point2f> = pts_v.into();
     let e = opencv::imgproc::fit_ellipse(&pts)?;
 
+    opencv::imgproc::ellipse_rotated_rect(mask, &e, color, -1, 8)?;
+
+    opencv::imgproc::bounding_rect(&pts)
+}
+
+fn draw_circle(
+    mask: &mut Mat,
+    color: Scalar,
+    topleft: Point2f,
+    downright: Point2f,
+) -> opencv::Result<Rect> {
+    let center = Point::new(
+        ((topleft.x + downright.x) / 2.0).round() as i32,
+        ((topleft.y + downright.y) / 2.0).round() as i32,
+    );
+    let radius = ((downright.x - topleft.x).min(downright.y - topleft.y) / 2.0).floor() as i32;
+
+    opencv::imgproc::circle(mask, center, radius, color, -1, 8, 0)?;
+
+    Ok(Rect_::new(
+        center.x - radius,
+        center.y - radius,
+        2 * radius + 1,
+        2 * radius + 1,
+    ))
+}
+
+fn draw_rounded_rectangle(
+    mask: &mut Mat,
+    color: Scalar,
+    rect: Rect,
+    radius: i32,
+) -> opencv::Result<Rect> {
+    let radius = radius.min(rect.width / 2).min(rect.height / 2).max(0);
+
+    // Two overlapping rectangles cover everything except for the corners
+    let horizontal = Rect_::new(
+        rect.x,
+        rect.y + radius,
+        rect.width,
+        rect.height - 2 * radius,
+    );
+    let vertical = Rect_::new(
+        rect.x + radius,
+        rect.y,
+        rect.width - 2 * radius,
+        rect.height,
+    );
+    opencv::imgproc::rectangle(mask, horizontal, color, -1, 8, 0)?;
+    opencv::imgproc::rectangle(mask, vertical, color, -1, 8, 0)?;
+
+    if radius > 0 {
+        let left = rect.x + radius;
+        let right = rect.x + rect.width - 1 - radius;
+        let top = rect.y + radius;
+        let bottom = rect.y + rect.height - 1 - radius;
+        for &(x, y) in &[(left, top), (right, top), (left, bottom), (right, bottom)] {
+            opencv::imgproc::circle(mask, Point::new(x, y), radius, color, -1, 8, 0)?;
+        }
+    }
+
+    Ok(rect)
+}
+
+fn draw_polygon(mask: &mut Mat, color: Scalar, points: &[(i32, i32)]) -> opencv::Result<Rect> {
+    let pts_v: Vec<Point> = points.iter().map(|&(x, y)| Point::new(x, y)).collect();
+    let pts: Vector<Point> = pts_v.into();
+
+    let mut polygons: Vector<Vector<Point>> = Vector::new();
+    polygons.push(pts.clone());
+    opencv::imgproc::fill_poly(mask, &polygons, color, 8, 0, Point::default())?;
+
+    opencv::imgproc::bounding_rect(&pts)
+}
+
+fn get_mask_on(
+    image_size: Size,
+    topleft: Point2f,
+    downright: Point2f,
+    shape: &MaskShape,
+) -> opencv::Result<(Mat, Rect)> {
     let init: Scalar = Scalar_::all(0.0);
     let red: Scalar = Scalar_::new(255.0, 0.0, 0.0, 0.0);
 
     let mut mask = Mat::new_size_with_default(image_size, IMG_TYPE, init)?;
-    opencv::imgproc::ellipse_rotated_rect(&mut mask, &e, red, -1, 8)?;
 
-    let br = opencv::imgproc::bounding_rect(&pts)?;
+    let region: Rect = Rect_::new(
+        topleft.x as i32,
+        topleft.y as i32,
+        (downright.x - topleft.x) as i32 + 1,
+        (downright.y - topleft.y) as i32 + 1,
+    );
+
+    let br = match shape {
+        MaskShape::Ellipse => draw_ellipse(&mut mask, red, topleft, downright)?,
+        MaskShape::Circle => draw_circle(&mut mask, red, topleft, downright)?,
+        MaskShape::Rectangle => draw_rounded_rectangle(&mut mask, red, region, 0)?,
+        MaskShape::RoundedRectangle { radius } => {
+            draw_rounded_rectangle(&mut mask, red, region, *radius)?
+        }
+        MaskShape::Polygon { points } => draw_polygon(&mut mask, red, points)?,
+    };
 
     Ok((mask, br))
 }
 
-fn process(image: Mat, topleft: Point2f, downright: Point2f) -> opencv::Result<Mat> {
+fn process(
+    image: Mat,
+    topleft: Point2f,
+    downright: Point2f,
+    shape: &MaskShape,
+) -> opencv::Result<Mat> {
     let sz: Size = image.size()?;
 
-    let (mask, bounding_rect) = get_mask_on(sz, topleft, downright)?;
+    let (mask, bounding_rect) = get_mask_on(sz, topleft, downright, shape)?;
 
     let background_color: Scalar = Scalar_::all(255.0);
     let fg = {
@@ -85,9 +188,15 @@ fn process(image: Mat, topleft: Point2f, downright: Point2f) -> opencv::Result<M
 fn crop_rect(bounding_rect: Rect, image_size: Size) -> Rect {
     let x = max(0, bounding_rect.x - BORDER_PIXELS);
     let y = max(0, bounding_rect.y - BORDER_PIXELS);
-    let w = min(image_size.width, bounding_rect.width + 2 * BORDER_PIXELS);
-    let h = min(image_size.height, bounding_rect.height + 2 * BORDER_PIXELS);
-    Rect_::new(x, y, w, h)
+    let x_end = min(
+        image_size.width,
+        bounding_rect.x + bounding_rect.width + BORDER_PIXELS,
+    );
+    let y_end = min(
+        image_size.height,
+        bounding_rect.y + bounding_rect.height + BORDER_PIXELS,
+    );
+    Rect_::new(x, y, x_end - x, y_end - y)
 }
 
 fn apply_rect_bounds(image: Mat, bounds: Rect) -> opencv::Result<Mat> {
@@ -96,28 +205,83 @@ fn apply_rect_bounds(image: Mat, bounds: Rect) -> opencv::Result<Mat> {
         .row_bounds(bounds.y, bounds.y + bounds.height)
 }
 
-fn inner(img: Mat) -> opencv::Result<Mat> {
+/// Clamps the selected region to the image, returning the corners (inclusive) for the mask
+fn region_of_interest(
+    sz: Size,
+    settings: Option<&RequestSettings>,
+) -> opencv::Result<(Point2f, Point2f)> {
+    let (start_x, start_y, end_x, end_y) = match settings {
+        Some(s) => (s.start_x, s.start_y, s.end_x, s.end_y),
+        None => (0, 0, sz.width, sz.height),
+    };
+
+    let start_x = start_x.clamp(0, max(0, sz.width - 1));
+    let start_y = start_y.clamp(0, max(0, sz.height - 1));
+    let end_x = end_x.clamp(start_x + 1, max(start_x + 1, sz.width));
+    let end_y = end_y.clamp(start_y + 1, max(start_y + 1, sz.height));
+
+    let min_size = 2 * BORDER_PIXELS + 2;
+    if end_x - start_x < min_size || end_y - start_y < min_size {
+        return Err(opencv::Error::new(
+            opencv::core::StsBadArg,
+            format!(
+                "selected region ({}, {})-({}, {}) is too small",
+                start_x, start_y, end_x, end_y
+            ),
+        ));
+    }
+
+    let topleft = Point2f::new(
+        (start_x + BORDER_PIXELS) as f32,
+        (start_y + BORDER_PIXELS) as f32,
+    );
+    let bottomright = Point2f::new(
+        (end_x - 1 - BORDER_PIXELS) as f32,
+        (end_y - 1 - BORDER_PIXELS) as f32,
+    );
+
+    Ok((topleft, bottomright))
+}
+
+fn inner(img: Mat, settings: Option<&RequestSettings>) -> opencv::Result<Mat> {
     let sz = img.size()?;
 
-    let max_width = sz.width - 1 - BORDER_PIXELS;
-    let max_height = sz.height - 1 - BORDER_PIXELS;
+    let (topleft, bottomright) = region_of_interest(sz, settings)?;
 
-    let topleft = Point2f::new(BORDER_PIXELS as f32, BORDER_PIXELS as f32);
-    let bottomright = Point2f::new(max_width as f32, max_height as f32);
+    let default_shape = MaskShape::default();
+    let shape = settings.map_or(&default_shape, |s| &s.mask);
 
-    let rr: Rect_<f32> = Rect_::from_points(topleft, bottomright);
+    process(img, topleft, bottomright, shape)
+}
 
-    process(img, topleft, bottomright)
+pub(crate) struct PreparedImage {
+    /// Processed heightmap (PNG)
+    pub(crate) data: Vec<u8>,
+    /// Width of the uploaded image
+    pub(crate) width: i32,
+    /// Height of the uploaded image
+    pub(crate) height: i32,
 }
 
-pub(crate) fn run(image_buffer: &[u8]) -> opencv::Result<Vec<u8>> {
+/// Turns an uploaded image into a heightmap
+///
+/// Without settings, the whole image is used.
+pub(crate) fn run(
+    image_buffer: &[u8],
+    settings: Option<&RequestSettings>,
+) -> opencv::Result<PreparedImage> {
     let buffer_v: Mat = Mat::from_slice(image_buffer)?;
     let img = opencv::imgcodecs::imdecode(&buffer_v, opencv::imgcodecs::IMREAD_GRAYSCALE)?;
+    let sz = img.size()?;
 
-    let result = inner(img)?;
+    let result = inner(img, settings)?;
 
     let mut out_buf = Vector::new();
     opencv::imgcodecs::imencode(".png", &result, &mut out_buf, &Vector::new())?;
 
-    Ok(out_buf.into())
+    Ok(PreparedImage {
+        data: out_buf.into(),
+        width: sz.width,
+        height: sz.height,
+    })
 }
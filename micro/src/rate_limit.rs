@@ -0,0 +1,231 @@
+use std::{fmt, rc::Rc};
+
+use actix::Addr;
+use actix_redis::{Command, RespValue};
+use actix_session::UserSession;
+use actix_web::{
+    dev::{
+        forward_ready, AnyBody, MessageBody, Service, ServiceRequest, ServiceResponse, Transform,
+    },
+    http::{header, StatusCode},
+    web, Error, HttpResponse, ResponseError,
+};
+
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+
+use log::{debug, error, warn};
+
+use fabseal_micro_common::{rate_limit_key, settings::RateLimit, RequestId};
+
+use crate::{
+    metrics,
+    redis_connection::RedisActor,
+    settings::Settings,
+    site::{job_token::JobTokens, types::FeedbackRequestInfo, util::REQUEST_ID_COOKIE_KEY},
+};
+
+/// Token bucket shared by all `fabseal-micro` instances
+///
+/// Takes one token from each bucket in `KEYS` if all of them have a token left.
+/// ARGV: capacity, refill rate in tokens per minute (at least 1).
+/// Returns 0 if the request is allowed, otherwise the number of milliseconds until it would be.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local rate = math.max(tonumber(ARGV[2]), 1) / 60000
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local tokens = {}
+local wait = 0
+for i, key in ipairs(KEYS) do
+    local bucket = redis.call('HMGET', key, 'tokens', 'ts')
+    local t = tonumber(bucket[1]) or capacity
+    local ts = tonumber(bucket[2]) or now
+    t = math.min(capacity, t + math.max(0, now - ts) * rate)
+    if t < 1 then
+        wait = math.max(wait, math.ceil((1 - t) / rate))
+    end
+    tokens[i] = t
+end
+
+if wait > 0 then
+    return wait
+end
+
+local ttl = math.ceil(capacity / rate)
+for i, key in ipairs(KEYS) do
+    redis.call('HSET', key, 'tokens', tostring(tokens[i] - 1), 'ts', tostring(now))
+    redis.call('PEXPIRE', key, ttl)
+end
+return 0
+"#;
+
+/// Error returned for requests over the rate limit
+#[derive(Debug)]
+struct RateLimited {
+    retry_after_secs: u64,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Too many requests, please try again later")
+    }
+}
+
+impl ResponseError for RateLimited {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, self.retry_after_secs.to_string()))
+            .body(self.to_string())
+    }
+}
+
+/// Takes a token from the given buckets, returns the milliseconds to wait if one of them is empty
+async fn take_token(
+    redis: &Addr<RedisActor>,
+    keys: &[String],
+    limit: &RateLimit,
+) -> Result<Option<u64>, String> {
+    let mut cmd: Vec<RespValue> = vec![
+        "EVAL".into(),
+        TOKEN_BUCKET_SCRIPT.into(),
+        keys.len().to_string().into(),
+    ];
+    cmd.extend(keys.iter().map(|key| key.as_str().into()));
+    cmd.push(limit.capacity.to_string().into());
+    cmd.push(limit.refill_per_minute.to_string().into());
+
+    let resp = redis
+        .send(Command(RespValue::Array(cmd)))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    match resp {
+        RespValue::Integer(0) => Ok(None),
+        RespValue::Integer(wait) => Ok(Some(wait.max(1) as u64)),
+        resp => Err(format!("unexpected response {:?}", resp)),
+    }
+}
+
+/// Limits requests per route by client IP and by the request id of the session
+/// (or the job token, or the `id` query parameter of the feedback API)
+///
+/// Limits are configured per route pattern in `limits.rate_limits`, other routes are not limited.
+/// Must be wrapped by the session middleware. If Redis is unavailable, requests are let through.
+pub(crate) struct RateLimiter;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+    B::Error: Into<Box<dyn std::error::Error + 'static>>,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub(crate) struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+}
+
+/// Returns the number of seconds the client has to wait, if the request is over the limit
+async fn check(req: &ServiceRequest) -> Option<u64> {
+    let route = req.match_pattern()?;
+    let settings = req.app_data::<web::Data<Settings>>()?;
+    let limit = settings.limits.rate_limits.get(&route)?.clone();
+    let redis = req.app_data::<web::Data<Addr<RedisActor>>>()?.clone();
+
+    let ip = if settings.http.trust_forwarded_headers {
+        req.connection_info()
+            .realip_remote_addr()
+            .map(|addr| addr.to_string())
+    } else {
+        req.peer_addr().map(|addr| addr.ip().to_string())
+    };
+
+    let mut keys = Vec::with_capacity(2);
+    if let Some(ip) = ip {
+        keys.push(rate_limit_key(&route, &format!("ip:{}", ip)));
+    }
+    let tokens = req.app_data::<web::Data<JobTokens>>();
+    let request_id = match tokens.and_then(|tokens| tokens.verify_header(req.headers())) {
+        Some(id) => Some(id),
+        None => req
+            .get_session()
+            .get::<RequestId>(REQUEST_ID_COOKIE_KEY)
+            .ok()
+            .flatten(),
+    };
+    // Feedback requests are identified by the `id` query parameter instead
+    let request_id = request_id.or_else(|| {
+        web::Query::<FeedbackRequestInfo>::from_query(req.query_string())
+            .ok()
+            .and_then(|info| info.id.parse().ok())
+    });
+    if let Some(id) = request_id {
+        keys.push(rate_limit_key(&route, &format!("request:{}", id)));
+    }
+    if keys.is_empty() {
+        return None;
+    }
+
+    match take_token(&redis, &keys, &limit).await {
+        Ok(None) => None,
+        Ok(Some(wait_ms)) => {
+            debug!("rate limited {:?} for {} ms", keys, wait_ms);
+            metrics::RATE_LIMITED.with_label_values(&[&route]).inc();
+            Some(wait_ms.div_ceil(1000))
+        }
+        Err(e) => {
+            error!(
+                "error while checking rate limit, letting request through: {}",
+                e
+            );
+            metrics::REDIS_ERRORS.with_label_values(&["EVAL"]).inc();
+            None
+        }
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+    B::Error: Into<Box<dyn std::error::Error + 'static>>,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            if let Some(retry_after_secs) = check(&req).await {
+                warn!("rate limit exceeded for {}", req.path());
+                // A response rather than an error, so that the CORS middleware still adds its headers
+                return Ok(req.error_response(RateLimited { retry_after_secs }));
+            }
+            let res = service.call(req).await?;
+            Ok(res.map_body(|_, body| AnyBody::from_message(body)))
+        })
+    }
+}
@@ -0,0 +1,258 @@
+//! Redis connection with authentication, database selection, TLS and Unix sockets
+//!
+//! `actix_redis::RedisActor` only connects to plain TCP addresses. `RedisActor` handles the same
+//! `Command` messages, so handlers can use either.
+
+use std::{collections::VecDeque, fs, io, time::Duration};
+
+use actix::prelude::*;
+use actix_redis::{Command, Error, RespValue};
+use log::{error, info, warn};
+use redis_async::{error::Error as RespError, resp::RespCodec, resp_array};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{split, AsyncRead, AsyncWrite, WriteHalf},
+    net::TcpStream,
+    sync::oneshot,
+};
+use tokio_util::codec::FramedRead;
+
+use fabseal_micro_common::settings::{RedisAddress, RedisConnectionSettings};
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+trait RedisStream: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> RedisStream for T {}
+
+type BoxedStream = Box<dyn RedisStream>;
+
+/// How to connect to the Redis server (shared by the actors of all HTTP workers)
+#[derive(Clone)]
+pub(crate) struct RedisConnector {
+    settings: RedisConnectionSettings,
+    tls: Option<tokio_native_tls::TlsConnector>,
+}
+
+impl RedisConnector {
+    pub(crate) fn new(settings: RedisConnectionSettings) -> io::Result<Self> {
+        let tls = match settings.address {
+            RedisAddress::Tcp { tls: true, .. } => {
+                let mut builder = native_tls::TlsConnector::builder();
+                if let Some(path) = &settings.tls_ca_file {
+                    let ca = native_tls::Certificate::from_pem(&fs::read(path)?)
+                        .map_err(io::Error::other)?;
+                    builder.add_root_certificate(ca);
+                }
+                Some(builder.build().map_err(io::Error::other)?.into())
+            }
+            _ => None,
+        };
+
+        Ok(RedisConnector { settings, tls })
+    }
+
+    /// Address for log messages
+    fn address(&self) -> String {
+        match &self.settings.address {
+            RedisAddress::Tcp { host, port, tls } => format!(
+                "{}://{}:{}",
+                if *tls { "rediss" } else { "redis" },
+                host,
+                port
+            ),
+            RedisAddress::Unix(path) => format!("unix://{}", path.display()),
+        }
+    }
+
+    async fn connect(self) -> io::Result<BoxedStream> {
+        match &self.settings.address {
+            RedisAddress::Tcp { host, port, .. } => {
+                let stream = TcpStream::connect((host.as_str(), *port)).await?;
+                stream.set_nodelay(true)?;
+                match &self.tls {
+                    Some(tls) => {
+                        let stream = tls.connect(host, stream).await.map_err(io::Error::other)?;
+                        Ok(Box::new(stream))
+                    }
+                    None => Ok(Box::new(stream)),
+                }
+            }
+            #[cfg(unix)]
+            RedisAddress::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            RedisAddress::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    /// Commands sent on a new connection before any other command
+    fn handshake(&self) -> Vec<RespValue> {
+        let mut commands = vec![];
+        if let Some(password) = &self.settings.password {
+            commands.push(match &self.settings.username {
+                Some(username) => resp_array!["AUTH", username.as_str(), password.as_str()],
+                None => resp_array!["AUTH", password.as_str()],
+            });
+        }
+        if self.settings.db != 0 {
+            commands.push(resp_array!["SELECT", self.settings.db.to_string()]);
+        }
+        commands
+    }
+}
+
+/// Redis connection actor, reconnects with increasing delays if connecting or the handshake fails
+pub(crate) struct RedisActor {
+    connector: RedisConnector,
+    reconnect_delay: Duration,
+    cell: Option<actix::io::FramedWrite<RespValue, WriteHalf<BoxedStream>, RespCodec>>,
+    queue: VecDeque<oneshot::Sender<Result<RespValue, Error>>>,
+}
+
+impl RedisActor {
+    pub(crate) fn start(connector: RedisConnector) -> Addr<RedisActor> {
+        Supervisor::start(|_| RedisActor {
+            connector,
+            reconnect_delay: MIN_RECONNECT_DELAY,
+            cell: None,
+            queue: VecDeque::new(),
+        })
+    }
+
+    fn write(&mut self, value: RespValue) -> oneshot::Receiver<Result<RespValue, Error>> {
+        let (tx, rx) = oneshot::channel();
+        match &mut self.cell {
+            Some(cell) => {
+                self.queue.push_back(tx);
+                cell.write(value);
+            }
+            None => {
+                let _ = tx.send(Err(Error::NotConnected));
+            }
+        }
+        rx
+    }
+
+    /// Authenticates and selects the database
+    ///
+    /// Commands sent in the meantime are queued behind the handshake by Redis.
+    fn handshake(&mut self, ctx: &mut Context<Self>) {
+        let replies: Vec<_> = self
+            .connector
+            .handshake()
+            .into_iter()
+            .map(|command| self.write(command))
+            .collect();
+
+        async move {
+            for reply in replies {
+                match reply.await.map_err(|_| Error::Disconnected) {
+                    Ok(Ok(RespValue::Error(e))) => return Err(e),
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) | Err(e) => return Err(e.to_string()),
+                }
+            }
+            Ok(())
+        }
+        .into_actor(self)
+        .map(|res, act, ctx| match res {
+            Ok(()) => act.reconnect_delay = MIN_RECONNECT_DELAY,
+            Err(e) => {
+                error!("Redis handshake failed: {}", e);
+                act.cell.take();
+                act.reconnect_later(ctx);
+            }
+        })
+        .spawn(ctx);
+    }
+
+    fn reconnect_later(&mut self, ctx: &mut Context<Self>) {
+        let delay = self.reconnect_delay;
+        self.reconnect_delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        // The supervisor restarts the stopped actor, which connects again
+        ctx.run_later(delay, |_, ctx| ctx.stop());
+    }
+}
+
+impl Actor for RedisActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.connector
+            .clone()
+            .connect()
+            .into_actor(self)
+            .map(|res, act, ctx| match res {
+                Ok(stream) => {
+                    info!("Connected to redis server: {}", act.connector.address());
+
+                    let (r, w) = split(stream);
+                    act.cell = Some(actix::io::FramedWrite::new(w, RespCodec, ctx));
+                    ctx.add_stream(FramedRead::new(r, RespCodec));
+
+                    act.handshake(ctx);
+                }
+                Err(e) => {
+                    error!(
+                        "Can not connect to redis server {}: {}",
+                        act.connector.address(),
+                        e
+                    );
+                    act.reconnect_later(ctx);
+                }
+            })
+            .wait(ctx);
+    }
+}
+
+impl Supervised for RedisActor {
+    fn restarting(&mut self, _: &mut Self::Context) {
+        self.cell.take();
+        for tx in self.queue.drain(..) {
+            let _ = tx.send(Err(Error::Disconnected));
+        }
+    }
+}
+
+impl actix::io::WriteHandler<io::Error> for RedisActor {
+    fn error(&mut self, err: io::Error, _: &mut Self::Context) -> Running {
+        warn!(
+            "Redis connection dropped: {} error: {}",
+            self.connector.address(),
+            err
+        );
+        Running::Stop
+    }
+}
+
+impl StreamHandler<Result<RespValue, RespError>> for RedisActor {
+    fn handle(&mut self, msg: Result<RespValue, RespError>, ctx: &mut Self::Context) {
+        match msg {
+            Err(e) => {
+                if let Some(tx) = self.queue.pop_front() {
+                    let _ = tx.send(Err(e.into()));
+                }
+                ctx.stop();
+            }
+            Ok(val) => {
+                if let Some(tx) = self.queue.pop_front() {
+                    let _ = tx.send(Ok(val));
+                }
+            }
+        }
+    }
+}
+
+impl Handler<Command> for RedisActor {
+    type Result = ResponseFuture<Result<RespValue, Error>>;
+
+    fn handle(&mut self, msg: Command, _: &mut Self::Context) -> Self::Result {
+        let rx = self.write(msg.0);
+        Box::pin(async move { rx.await.map_err(|_| Error::Disconnected)? })
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    error::Error as StdError,
+    fs, io,
+    path::Path,
+    task::{Context, Poll},
+};
+
+use actix_session::{CookieSession, UserSession};
+use actix_web::{
+    body::MessageBody,
+    cookie::{Cookie, CookieJar, Key},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, HeaderValue},
+    Error, HttpMessage,
+};
+
+use futures_util::future::{ready, Either, LocalBoxFuture, Ready};
+
+use log::{debug, warn};
+
+use rand::Rng;
+
+use crate::settings::Settings;
+
+mod redis;
+pub(crate) use self::redis::{revoke_session, RedisSession};
+
+pub(crate) const SESSION_COOKIE_NAME: &str = "fabseal_session";
+
+/// `CookieSession` panics for shorter keys
+const MIN_KEY_LENGTH: usize = 32;
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn read_key_file(path: &Path) -> io::Result<Vec<u8>> {
+    let key = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot read {}: {}", path.display(), e)))?;
+    Ok(key.trim_end_matches(&['\r', '\n'][..]).as_bytes().to_vec())
+}
+
+fn check_length(key: Vec<u8>, setting: &str) -> io::Result<Vec<u8>> {
+    if key.len() < MIN_KEY_LENGTH {
+        return Err(invalid_input(format!(
+            "{} has to be at least {} bytes long",
+            setting, MIN_KEY_LENGTH
+        )));
+    }
+    Ok(key)
+}
+
+/// The key for encrypting session cookies and previous keys that are still accepted
+#[derive(Clone)]
+pub(crate) struct SessionKeys {
+    pub(crate) current: Vec<u8>,
+    previous: Vec<Key>,
+}
+
+impl SessionKeys {
+    /// Loads the configured keys
+    ///
+    /// Without a key, a random key is generated in debug mode and an error is returned otherwise.
+    pub(crate) fn load(settings: &Settings) -> io::Result<Self> {
+        let session = &settings.session;
+
+        let current = match (&session.key, &session.key_file) {
+            (Some(_), Some(_)) => {
+                return Err(invalid_input(
+                    "only one of session.key and session.key_file may be set".to_string(),
+                ))
+            }
+            (Some(key), None) => check_length(key.as_bytes().to_vec(), "session.key")?,
+            (None, Some(path)) => check_length(read_key_file(path)?, "session.key_file")?,
+            (None, None) if settings.debug => {
+                warn!("no session key configured, sessions will be lost on restart");
+                let key: [u8; 32] = rand::thread_rng().gen();
+                key.to_vec()
+            }
+            (None, None) => {
+                return Err(invalid_input(
+                    "session.key or session.key_file has to be set (unless debug is enabled)"
+                        .to_string(),
+                ))
+            }
+        };
+
+        let mut previous = vec![];
+        for key in &session.previous_keys {
+            let key = check_length(key.as_bytes().to_vec(), "session.previous_keys")?;
+            previous.push(Key::derive_from(&key));
+        }
+        for path in &session.previous_key_files {
+            let key = check_length(read_key_file(path)?, "session.previous_key_files")?;
+            previous.push(Key::derive_from(&key));
+        }
+
+        Ok(SessionKeys { current, previous })
+    }
+
+    /// Signing keys derived from the current key (first) and the previous keys
+    pub(crate) fn signing_keys(&self) -> Vec<Vec<u8>> {
+        std::iter::once(&Key::derive_from(&self.current))
+            .chain(&self.previous)
+            .map(|key| key.signing().to_vec())
+            .collect()
+    }
+}
+
+/// Marks requests with a session cookie that was encrypted with a previous key
+struct RotatedSessionKey;
+
+/// Makes the session middleware send a cookie encrypted with the current key, if the request
+/// came with a cookie encrypted with a previous key
+///
+/// Has to be called within the session middleware.
+pub(crate) fn renew_rotated_session(req: &ServiceRequest) {
+    if req.extensions().get::<RotatedSessionKey>().is_some() {
+        req.get_session().renew();
+    }
+}
+
+/// Accepts session cookies encrypted with a previous key
+///
+/// Has to wrap the session middleware: such cookies are re-encrypted with the current key
+/// before the session middleware reads them.
+pub(crate) struct SessionKeyRotation {
+    keys: SessionKeys,
+}
+
+impl SessionKeyRotation {
+    pub(crate) fn new(keys: SessionKeys) -> Self {
+        SessionKeyRotation { keys }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SessionKeyRotation
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SessionKeyRotationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SessionKeyRotationMiddleware {
+            service,
+            current: Key::derive_from(&self.keys.current),
+            previous: self.keys.previous.clone(),
+        }))
+    }
+}
+
+pub(crate) struct SessionKeyRotationMiddleware<S> {
+    service: S,
+    current: Key,
+    previous: Vec<Key>,
+}
+
+impl<S> SessionKeyRotationMiddleware<S> {
+    /// Re-encrypts the session cookie with the current key, if it was encrypted with a previous key
+    fn reencrypt(&self, cookie: Cookie<'static>) -> Option<String> {
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie);
+
+        if jar
+            .private(&self.current)
+            .get(SESSION_COOKIE_NAME)
+            .is_some()
+        {
+            return None;
+        }
+        let cookie = self
+            .previous
+            .iter()
+            .find_map(|key| jar.private(key).get(SESSION_COOKIE_NAME))?;
+
+        let mut renewed = CookieJar::new();
+        renewed.private_mut(&self.current).add(cookie);
+        renewed
+            .get(SESSION_COOKIE_NAME)
+            .map(|cookie| cookie.encoded().to_string())
+    }
+
+    /// Replaces a session cookie encrypted with a previous key in the `Cookie` header
+    ///
+    /// Returns whether the cookie was replaced.
+    fn rotate(&self, req: &mut ServiceRequest) -> bool {
+        if self.previous.is_empty() {
+            return false;
+        }
+
+        let mut rotated = false;
+        let mut pairs = vec![];
+        for value in req.headers().get_all(header::COOKIE) {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => return false,
+            };
+            for pair in value.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+                let renewed = match Cookie::parse_encoded(pair) {
+                    Ok(cookie) if cookie.name() == SESSION_COOKIE_NAME => {
+                        self.reencrypt(cookie.into_owned())
+                    }
+                    _ => None,
+                };
+                match renewed {
+                    Some(renewed) => {
+                        rotated = true;
+                        pairs.push(renewed);
+                    }
+                    None => pairs.push(pair.to_string()),
+                }
+            }
+        }
+        if !rotated {
+            return false;
+        }
+
+        match HeaderValue::from_str(&pairs.join("; ")) {
+            Ok(value) => {
+                req.headers_mut().insert(header::COOKIE, value);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for SessionKeyRotationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        if self.rotate(&mut req) {
+            debug!("session cookie encrypted with a previous key");
+            req.extensions_mut().insert(RotatedSessionKey);
+        }
+        self.service.call(req)
+    }
+}
+
+/// Session middleware selected by `session.backend`
+pub(crate) enum SessionStore {
+    Cookie(CookieSession),
+    Redis(RedisSession),
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SessionStore
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+    B::Error: StdError,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Transform = SessionStoreMiddleware<
+        <CookieSession as Transform<S, ServiceRequest>>::Transform,
+        <RedisSession as Transform<S, ServiceRequest>>::Transform,
+    >;
+    type InitError = ();
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        match self {
+            SessionStore::Cookie(store) => {
+                let fut = store.new_transform(service);
+                Box::pin(async move { Ok(SessionStoreMiddleware::Cookie(fut.await?)) })
+            }
+            SessionStore::Redis(store) => {
+                let fut = store.new_transform(service);
+                Box::pin(async move { Ok(SessionStoreMiddleware::Redis(fut.await?)) })
+            }
+        }
+    }
+}
+
+pub(crate) enum SessionStoreMiddleware<C, R> {
+    Cookie(C),
+    Redis(R),
+}
+
+impl<C, R> Service<ServiceRequest> for SessionStoreMiddleware<C, R>
+where
+    C: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    R: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = Either<C::Future, R::Future>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self {
+            SessionStoreMiddleware::Cookie(service) => service.poll_ready(cx),
+            SessionStoreMiddleware::Redis(service) => service.poll_ready(cx),
+        }
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        match self {
+            SessionStoreMiddleware::Cookie(service) => Either::Left(service.call(req)),
+            SessionStoreMiddleware::Redis(service) => Either::Right(service.call(req)),
+        }
+    }
+}
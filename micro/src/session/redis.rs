@@ -0,0 +1,379 @@
+use std::{collections::HashMap, error::Error as StdError, iter, rc::Rc};
+
+use actix::Addr;
+use actix_redis::{Command, RespValue};
+use actix_session::{Session, SessionStatus};
+use actix_web::{
+    body::{AnyBody, MessageBody},
+    cookie::{Cookie, CookieJar, Key, SameSite},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, HeaderValue},
+    Error,
+};
+
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+
+use log::{debug, error};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+use time::{Duration, OffsetDateTime};
+
+use fabseal_micro_common::{session_key, session_registry_key, unix_timestamp};
+use redis_async::resp_array;
+
+use crate::{
+    redis_connection::RedisActor,
+    site::util::{convert_string_response, redis_error},
+};
+
+const SESSION_ID_LENGTH: usize = 32;
+
+/// Stores the state of a session and registers it in the session registry,
+/// dropping expired sessions from the registry so that it does not grow without bound
+///
+/// KEYS: session hash, session registry.
+/// ARGV: state (JSON), current time, TTL in seconds, session id.
+const SAVE_SESSION_SCRIPT: &str = r#"
+local now = tonumber(ARGV[2])
+local ttl = tonumber(ARGV[3])
+redis.call('HSET', KEYS[1], 'state', ARGV[1], 'updated_at', now)
+redis.call('HSETNX', KEYS[1], 'created_at', now)
+redis.call('EXPIRE', KEYS[1], ttl)
+redis.call('ZREMRANGEBYSCORE', KEYS[2], '-inf', now)
+redis.call('ZADD', KEYS[2], now + ttl, ARGV[4])
+return 0
+"#;
+
+/// Deletes a session stored in Redis, returns whether it existed
+pub(crate) async fn revoke_session(
+    redis: &Addr<RedisActor>,
+    session_id: &str,
+) -> Result<bool, Error> {
+    let resp = redis
+        .send(Command(resp_array!["DEL", session_key(session_id)]))
+        .await
+        .map_err(redis_error("DEL"))?
+        .map_err(redis_error("DEL"))?;
+
+    redis
+        .send(Command(resp_array![
+            "ZREM",
+            session_registry_key(),
+            session_id
+        ]))
+        .await
+        .map_err(redis_error("ZREM"))?
+        .map_err(redis_error("ZREM"))?;
+
+    match resp {
+        RespValue::Integer(deleted) => Ok(deleted > 0),
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            Err(actix_web::error::ErrorInternalServerError("Redis error"))
+        }
+    }
+}
+
+/// Session middleware keeping the session state in Redis
+///
+/// The (encrypted) session cookie only holds the session id, so sessions can be listed and revoked.
+pub(crate) struct RedisSession(Rc<Inner>);
+
+impl RedisSession {
+    pub(crate) fn new(redis: Addr<RedisActor>, key: &[u8]) -> RedisSession {
+        RedisSession(Rc::new(Inner {
+            key: Key::derive_from(key),
+            redis,
+            ttl: 7200,
+            name: "actix-session".to_owned(),
+            path: "/".to_owned(),
+            domain: None,
+            secure: false,
+            max_age: None,
+            same_site: None,
+            http_only: true,
+        }))
+    }
+
+    /// Time to live of the session state in seconds
+    pub(crate) fn ttl(mut self, ttl: u32) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().ttl = ttl;
+        self
+    }
+
+    pub(crate) fn cookie_name(mut self, name: &str) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().name = name.to_owned();
+        self
+    }
+
+    pub(crate) fn cookie_path(mut self, path: &str) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().path = path.to_owned();
+        self
+    }
+
+    pub(crate) fn cookie_domain(mut self, domain: &str) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().domain = Some(domain.to_owned());
+        self
+    }
+
+    pub(crate) fn cookie_secure(mut self, secure: bool) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().secure = secure;
+        self
+    }
+
+    pub(crate) fn cookie_max_age(mut self, max_age: Duration) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().max_age = Some(max_age);
+        self
+    }
+
+    pub(crate) fn cookie_same_site(mut self, same_site: SameSite) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().same_site = Some(same_site);
+        self
+    }
+
+    pub(crate) fn cookie_http_only(mut self, http_only: bool) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().http_only = http_only;
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RedisSession
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+    B::Error: StdError,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Transform = RedisSessionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RedisSessionMiddleware {
+            service: Rc::new(service),
+            inner: Rc::clone(&self.0),
+        }))
+    }
+}
+
+pub(crate) struct RedisSessionMiddleware<S> {
+    service: Rc<S>,
+    inner: Rc<Inner>,
+}
+
+impl<S, B> Service<ServiceRequest> for RedisSessionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+    B::Error: StdError,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let inner = Rc::clone(&self.inner);
+
+        Box::pin(async move {
+            let session_id = match inner.load(&req).await? {
+                Some((session_id, state)) => {
+                    Session::set_session(&mut req, state);
+                    Some(session_id)
+                }
+                None => None,
+            };
+
+            let mut res = service.call(req).await?;
+
+            let (status, state) = Session::get_changes(&mut res);
+            let state: Vec<_> = state.collect();
+            match status {
+                SessionStatus::Unchanged => {}
+                // New sessions are only stored once the handler inserted state, otherwise every
+                // request outside of the cookie path would create a session
+                SessionStatus::Changed if session_id.is_none() && state.is_empty() => {}
+                SessionStatus::Changed => inner.save(&mut res, session_id, state).await?,
+                SessionStatus::Renewed => {
+                    if let Some(session_id) = session_id {
+                        revoke_session(&inner.redis, &session_id).await?;
+                    }
+                    if !state.is_empty() {
+                        inner.save(&mut res, None, state).await?
+                    }
+                }
+                SessionStatus::Purged => {
+                    if let Some(session_id) = session_id {
+                        revoke_session(&inner.redis, &session_id).await?;
+                    }
+                    inner.remove_cookie(&mut res)?
+                }
+            }
+
+            Ok(res.map_body(|_, body| AnyBody::from_message(body)))
+        })
+    }
+}
+
+struct Inner {
+    key: Key,
+    redis: Addr<RedisActor>,
+    ttl: u32,
+    name: String,
+    path: String,
+    domain: Option<String>,
+    secure: bool,
+    max_age: Option<Duration>,
+    same_site: Option<SameSite>,
+    http_only: bool,
+}
+
+impl Inner {
+    /// Reads the session id from the cookie and the session state from Redis
+    ///
+    /// Returns `None` for requests without a valid cookie and for expired or revoked sessions.
+    async fn load(
+        &self,
+        req: &ServiceRequest,
+    ) -> Result<Option<(String, HashMap<String, String>)>, Error> {
+        // Do not hold the cookies across the await point
+        let session_id = {
+            let cookies = match req.cookies() {
+                Ok(cookies) => cookies,
+                Err(_) => return Ok(None),
+            };
+            let cookie = match cookies.iter().find(|cookie| cookie.name() == self.name) {
+                Some(cookie) => cookie.clone(),
+                None => return Ok(None),
+            };
+
+            let mut jar = CookieJar::new();
+            jar.add_original(cookie);
+            match jar.private(&self.key).get(&self.name) {
+                Some(cookie) => cookie.value().to_string(),
+                None => return Ok(None),
+            }
+        };
+
+        let resp = self
+            .redis
+            .send(Command(resp_array![
+                "HGET",
+                session_key(&session_id),
+                "state"
+            ]))
+            .await
+            .map_err(redis_error("HGET"))?
+            .map_err(redis_error("HGET"))?;
+
+        if resp == RespValue::Nil {
+            debug!("session {} has expired or was revoked", session_id);
+            return Ok(None);
+        }
+
+        match serde_json::from_str(&convert_string_response(resp)?) {
+            Ok(state) => Ok(Some((session_id, state))),
+            Err(e) => {
+                error!("invalid state of session {}: {}", session_id, e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Stores the session state, creating a new session (and cookie) if `session_id` is `None`
+    async fn save<B>(
+        &self,
+        res: &mut ServiceResponse<B>,
+        session_id: Option<String>,
+        state: Vec<(String, String)>,
+    ) -> Result<(), Error> {
+        let state: HashMap<String, String> = state.into_iter().collect();
+        let state = serde_json::to_string(&state)?;
+
+        let (session_id, is_new) = match session_id {
+            Some(session_id) => (session_id, false),
+            None => {
+                let mut rng = rand::thread_rng();
+                let session_id: String = iter::repeat(())
+                    .map(|()| char::from(rng.sample(Alphanumeric)))
+                    .take(SESSION_ID_LENGTH)
+                    .collect();
+                (session_id, true)
+            }
+        };
+
+        let resp = self
+            .redis
+            .send(Command(resp_array![
+                "EVAL",
+                SAVE_SESSION_SCRIPT,
+                "2",
+                session_key(&session_id),
+                session_registry_key(),
+                state,
+                unix_timestamp().to_string(),
+                self.ttl.to_string(),
+                session_id.as_str()
+            ]))
+            .await
+            .map_err(redis_error("EVAL"))?
+            .map_err(redis_error("EVAL"))?;
+
+        if let RespValue::Error(e) = resp {
+            error!("Redis error: {}", e);
+            return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+        }
+
+        if is_new {
+            self.set_cookie(res, session_id)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_cookie<B>(&self, res: &mut ServiceResponse<B>, session_id: String) -> Result<(), Error> {
+        let mut cookie = Cookie::new(self.name.clone(), session_id);
+        cookie.set_path(self.path.clone());
+        cookie.set_secure(self.secure);
+        cookie.set_http_only(self.http_only);
+
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        if let Some(max_age) = self.max_age {
+            cookie.set_max_age(max_age);
+        }
+        if let Some(same_site) = self.same_site {
+            cookie.set_same_site(same_site);
+        }
+
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.key).add(cookie);
+
+        for cookie in jar.delta() {
+            let val = HeaderValue::from_str(&cookie.encoded().to_string())?;
+            res.headers_mut().append(header::SET_COOKIE, val);
+        }
+
+        Ok(())
+    }
+
+    fn remove_cookie<B>(&self, res: &mut ServiceResponse<B>) -> Result<(), Error> {
+        let mut cookie = Cookie::named(self.name.clone());
+        cookie.set_path(self.path.clone());
+        cookie.set_value("");
+        cookie.set_max_age(Duration::zero());
+        cookie.set_expires(OffsetDateTime::now_utc() - Duration::days(365));
+
+        let val = HeaderValue::from_str(&cookie.to_string())?;
+        res.headers_mut().append(header::SET_COOKIE, val);
+
+        Ok(())
+    }
+}
@@ -1,8 +1,54 @@
+use std::{fmt, path::PathBuf};
+
 use config::{Config, ConfigError, Environment, File};
 
 use fabseal_micro_common::settings::{HttpSettings, Limits, RedisSettings};
 use serde::Deserialize;
 
+/// Access to the admin API (`/api/v1/admin`)
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AdminSettings {
+    /// Bearer token required for the admin API, the admin API is disabled if unset
+    pub token: Option<String>,
+}
+
+/// Where session state is stored
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionBackend {
+    /// In the encrypted session cookie, nothing is stored server-side
+    #[default]
+    Cookie,
+    /// In Redis (expiring after `limits.session_ttl`), the session cookie only holds the session id
+    Redis,
+}
+
+/// Session storage and the keys for encrypting the session cookie (at least 32 bytes each)
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SessionSettings {
+    pub backend: SessionBackend,
+    pub key: Option<String>,
+    /// File containing the key (instead of `key`)
+    pub key_file: Option<PathBuf>,
+    /// Keys that are still accepted for sessions created before a key rotation
+    pub previous_keys: Vec<String>,
+    pub previous_key_files: Vec<PathBuf>,
+}
+
+impl fmt::Debug for SessionSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionSettings")
+            .field("backend", &self.backend)
+            .field("key", &self.key.as_ref().map(|_| "<redacted>"))
+            .field("key_file", &self.key_file)
+            .field("previous_keys", &self.previous_keys.len())
+            .field("previous_key_files", &self.previous_key_files)
+            .finish()
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(default)]
 pub struct Settings {
@@ -10,6 +56,8 @@ pub struct Settings {
     pub http: HttpSettings,
     pub redis: RedisSettings,
     pub limits: Limits,
+    pub admin: AdminSettings,
+    pub session: SessionSettings,
 }
 
 impl Default for Settings {
@@ -19,6 +67,8 @@ impl Default for Settings {
             http: HttpSettings::default(),
             redis: RedisSettings::default(),
             limits: Limits::default(),
+            admin: AdminSettings::default(),
+            session: SessionSettings::default(),
         }
     }
 }
@@ -0,0 +1,373 @@
+use actix::Addr;
+use actix_redis::{Command, RespValue};
+use std::collections::HashMap;
+
+use actix_web::{
+    delete, dev::Payload, get, http::header, web, FromRequest, HttpRequest, HttpResponse,
+    Result as AWResult,
+};
+
+use futures_util::future::{ready, Ready};
+
+use log::{debug, error, info, warn};
+
+use fabseal_micro_common::*;
+use redis_async::resp_array;
+
+use crate::{
+    redis_connection::RedisActor,
+    session::revoke_session,
+    settings::{SessionBackend, Settings},
+    site::{queue::submission_consumers, types::*, util::*},
+};
+
+const DEFAULT_SESSION_PAGE_SIZE: u64 = 50;
+const MAX_SESSION_PAGE_SIZE: u64 = 500;
+
+/// Extractor that only succeeds for requests carrying the admin bearer token
+pub(crate) struct Admin;
+
+/// Compares without returning early, so that the token cannot be guessed from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn authorize(req: &HttpRequest) -> AWResult<Admin> {
+    let token = req
+        .app_data::<web::Data<Settings>>()
+        .and_then(|settings| settings.admin.token.clone())
+        .ok_or_else(|| actix_web::error::ErrorForbidden("Admin API is disabled"))?;
+
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => Ok(Admin),
+        _ => {
+            warn!("unauthorized admin request to {}", req.path());
+            Err(actix_web::error::ErrorUnauthorized("Invalid admin token"))
+        }
+    }
+}
+
+impl FromRequest for Admin {
+    type Config = ();
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authorize(req))
+    }
+}
+
+fn parse_request_id(id: &str) -> AWResult<RequestId> {
+    id.parse().map_err(|e| {
+        debug!("invalid request id {:?}: {}", id, e);
+        actix_web::error::ErrorBadRequest("Invalid request id")
+    })
+}
+
+/// Output of the mesh backend for the last attempt of a job
+#[get("/jobs/{id}/log")]
+async fn job_log(
+    _admin: Admin,
+    redis: web::Data<Addr<RedisActor>>,
+    path: web::Path<String>,
+) -> AWResult<HttpResponse> {
+    let id = parse_request_id(&path)?;
+    info!("job_log request-id: {}", id);
+
+    let resp = redis
+        .send(Command(resp_array!["GET", log_key(id)]))
+        .await
+        .map_err(redis_error("GET"))?
+        .map_err(redis_error("GET"))?;
+
+    let log = convert_bytes_response(resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(log))
+}
+
+/// Reads the heartbeat of a worker, `None` if it has expired
+async fn worker_heartbeat(
+    redis: &Addr<RedisActor>,
+    consumer_name: &str,
+) -> AWResult<Option<WorkerInfoResponse>> {
+    let resp = redis
+        .send(Command(resp_array!["HGETALL", worker_key(consumer_name)]))
+        .await
+        .map_err(redis_error("HGETALL"))?
+        .map_err(redis_error("HGETALL"))?;
+
+    let mut map = convert_map_response(resp)?;
+    if map.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(WorkerInfoResponse {
+        consumer: consumer_name.to_string(),
+        alive: true,
+        host: take_string_field(&mut map, "host")?,
+        pid: take_integer_field(&mut map, "pid")?,
+        current_request_id: take_string_field(&mut map, "current_request_id")?
+            .filter(|id| !id.is_empty()),
+        jobs_done: take_integer_field(&mut map, "jobs_done")?,
+        jobs_failed: take_integer_field(&mut map, "jobs_failed")?,
+        started_at: take_integer_field(&mut map, "started_at")?,
+        last_seen: take_integer_field(&mut map, "last_seen")?,
+        backend: take_string_field(&mut map, "backend")?,
+        backend_version: take_string_field(&mut map, "backend_version")?,
+        version: take_string_field(&mut map, "version")?,
+        ..Default::default()
+    }))
+}
+
+/// Workers with a recent heartbeat and consumers of the submission consumer group
+#[get("/workers")]
+async fn list_workers(_admin: Admin, redis: web::Data<Addr<RedisActor>>) -> AWResult<HttpResponse> {
+    info!("list_workers");
+
+    let resp = redis
+        .send(Command(resp_array!["SMEMBERS", worker_registry_key()]))
+        .await
+        .map_err(redis_error("SMEMBERS"))?
+        .map_err(redis_error("SMEMBERS"))?;
+
+    let names = match resp {
+        RespValue::Array(names) => names
+            .into_iter()
+            .map(convert_string_response)
+            .collect::<AWResult<Vec<String>>>()?,
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+        }
+    };
+
+    let mut workers = Vec::with_capacity(names.len());
+    for name in names {
+        match worker_heartbeat(&redis, &name).await? {
+            Some(worker) => workers.push(worker),
+            None => {
+                // The worker died without unregistering
+                debug!("removing expired worker {} from registry", name);
+                redis
+                    .send(Command(resp_array![
+                        "SREM",
+                        worker_registry_key(),
+                        name.as_str()
+                    ]))
+                    .await
+                    .map_err(redis_error("SREM"))?
+                    .map_err(redis_error("SREM"))?;
+            }
+        }
+    }
+
+    for consumer in submission_consumers(&redis).await? {
+        let pos = workers.iter().position(|w| w.consumer == consumer.name);
+        let worker = match pos {
+            Some(pos) => &mut workers[pos],
+            None => {
+                workers.push(WorkerInfoResponse {
+                    consumer: consumer.name.clone(),
+                    ..Default::default()
+                });
+                workers.last_mut().unwrap()
+            }
+        };
+        worker.pending = Some(consumer.pending);
+        worker.idle_ms = Some(consumer.idle_ms);
+    }
+
+    workers.sort_by(|a, b| a.consumer.cmp(&b.consumer));
+
+    Ok(HttpResponse::Ok().json(WorkersResponse { workers }))
+}
+
+fn require_redis_sessions(settings: &Settings) -> AWResult<()> {
+    match settings.session.backend {
+        SessionBackend::Redis => Ok(()),
+        SessionBackend::Cookie => Err(actix_web::error::ErrorNotFound(
+            "Sessions are not stored server-side",
+        )),
+    }
+}
+
+/// Reads a session, `None` if it has expired or was revoked
+async fn session_info(
+    redis: &Addr<RedisActor>,
+    session_id: String,
+    expires_at: i64,
+) -> AWResult<Option<SessionInfoResponse>> {
+    let resp = redis
+        .send(Command(resp_array![
+            "HMGET",
+            session_key(&session_id),
+            "state",
+            "created_at",
+            "updated_at"
+        ]))
+        .await
+        .map_err(redis_error("HMGET"))?
+        .map_err(redis_error("HMGET"))?;
+
+    let mut fields = match resp {
+        RespValue::Array(fields) if fields.len() == 3 => fields.into_iter(),
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+        }
+    };
+    let state = match fields.next() {
+        Some(RespValue::Nil) | None => return Ok(None),
+        Some(state) => convert_string_response(state)?,
+    };
+    let mut map: HashMap<String, RespValue> = ["created_at", "updated_at"]
+        .iter()
+        .map(|field| field.to_string())
+        .zip(fields)
+        .filter(|(_, value)| *value != RespValue::Nil)
+        .collect();
+
+    let request_id = serde_json::from_str::<HashMap<String, String>>(&state)
+        .ok()
+        .and_then(|state| state.get(REQUEST_ID_COOKIE_KEY).cloned())
+        .and_then(|id| serde_json::from_str::<RequestId>(&id).ok())
+        .map(|id| id.to_string());
+
+    Ok(Some(SessionInfoResponse {
+        id: session_id,
+        request_id,
+        created_at: take_integer_field(&mut map, "created_at")?,
+        updated_at: take_integer_field(&mut map, "updated_at")?,
+        expires_at,
+    }))
+}
+
+/// Sessions stored in Redis (`session.backend = "redis"`)
+#[get("/sessions")]
+async fn list_sessions(
+    _admin: Admin,
+    settings: web::Data<Settings>,
+    redis: web::Data<Addr<RedisActor>>,
+    info: web::Query<SessionListInfo>,
+) -> AWResult<HttpResponse> {
+    info!("list_sessions query={:?}", info);
+    require_redis_sessions(&settings)?;
+    let limit = info
+        .limit
+        .unwrap_or(DEFAULT_SESSION_PAGE_SIZE)
+        .clamp(1, MAX_SESSION_PAGE_SIZE);
+
+    redis
+        .send(Command(resp_array![
+            "ZREMRANGEBYSCORE",
+            session_registry_key(),
+            "-inf",
+            unix_timestamp().to_string()
+        ]))
+        .await
+        .map_err(redis_error("ZREMRANGEBYSCORE"))?
+        .map_err(redis_error("ZREMRANGEBYSCORE"))?;
+
+    let resp = redis
+        .send(Command(resp_array!["ZCARD", session_registry_key()]))
+        .await
+        .map_err(redis_error("ZCARD"))?
+        .map_err(redis_error("ZCARD"))?;
+    let total = match resp {
+        RespValue::Integer(total) => total.max(0) as u64,
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+        }
+    };
+
+    // Same as the feedback list, pages past the end are empty
+    let entries = if info.offset < total {
+        let resp = redis
+            .send(Command(resp_array![
+                "ZRANGE",
+                session_registry_key(),
+                info.offset.to_string(),
+                (info.offset + (limit - 1)).min(total - 1).to_string(),
+                "WITHSCORES"
+            ]))
+            .await
+            .map_err(redis_error("ZRANGE"))?
+            .map_err(redis_error("ZRANGE"))?;
+        match resp {
+            RespValue::Array(entries) => entries,
+            _ => {
+                error!("Unexpected Redis response: {:?}", resp);
+                return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+            }
+        }
+    } else {
+        vec![]
+    };
+
+    let mut sessions = Vec::with_capacity(entries.len() / 2);
+    let mut entries = entries.into_iter();
+    while let (Some(id), Some(score)) = (entries.next(), entries.next()) {
+        let id = convert_string_response(id)?;
+        let expires_at = convert_string_response(score)?.parse().unwrap_or(0);
+        match session_info(&redis, id.clone(), expires_at).await? {
+            Some(session) => sessions.push(session),
+            None => {
+                // The session was deleted without unregistering
+                debug!("removing deleted session {} from registry", id);
+                redis
+                    .send(Command(resp_array![
+                        "ZREM",
+                        session_registry_key(),
+                        id.as_str()
+                    ]))
+                    .await
+                    .map_err(redis_error("ZREM"))?
+                    .map_err(redis_error("ZREM"))?;
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(SessionsResponse {
+        total,
+        offset: info.offset,
+        limit,
+        sessions,
+    }))
+}
+
+/// Ends a session stored in Redis, the next request of the client starts a new session
+#[delete("/sessions/{id}")]
+async fn delete_session(
+    _admin: Admin,
+    settings: web::Data<Settings>,
+    redis: web::Data<Addr<RedisActor>>,
+    path: web::Path<String>,
+) -> AWResult<HttpResponse> {
+    info!("delete_session {}", path);
+    require_redis_sessions(&settings)?;
+
+    if revoke_session(&redis, &path).await? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(actix_web::error::ErrorNotFound("Unknown session"))
+    }
+}
+
+pub(crate) fn admin_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .service(job_log)
+            .service(list_workers)
+            .service(list_sessions)
+            .service(delete_session),
+    );
+}
@@ -1,56 +1,215 @@
 use actix::Addr;
 use actix_multipart as mp;
-use actix_redis::{Command, RedisActor, RespValue};
+use actix_redis::{Command, RespValue};
 use actix_session::Session;
 use actix_web::{
     get,
-    http::header::{ContentDisposition, DispositionParam, DispositionType},
-    post, web, HttpResponse, Result as AWResult,
+    http::header::{self, ContentDisposition, DispositionParam, DispositionType},
+    post, web, HttpResponse, HttpResponseBuilder, Result as AWResult,
 };
 
+use std::fmt;
+
 use futures_util::TryStreamExt;
 
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 
 use fabseal_micro_common::*;
 use redis_async::resp_array;
 
 use crate::{
+    metrics,
     prepare_image::run,
+    redis_connection::RedisActor,
     settings::Settings,
-    site::{types::*, util::*},
+    site::{
+        job_token::{ActiveRequest, JobTokens},
+        queue::{queue_position, submit_job, trim_acknowledged},
+        types::*,
+        util::*,
+    },
 };
 
+/// Suggested delay for clients when the queue is full
+const QUEUE_FULL_RETRY_AFTER_SECONDS: u32 = 30;
+
+fn result_response_builder(
+    result_type: &ResultType,
+    name: &dyn fmt::Display,
+) -> HttpResponseBuilder {
+    let mut response_builder = HttpResponse::Ok();
+
+    match result_type {
+        ResultType::Heightmap => {
+            response_builder.content_type("image/png");
+        }
+        ResultType::Model => {
+            let cd = ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(format!("model_{}.stl", name))],
+            };
+            response_builder.append_header(cd);
+            response_builder.content_type("model/stl");
+        }
+    };
+
+    response_builder
+}
+
 #[get("/public/result")]
-async fn fetch_model(info: web::Query<ResultRequestInfo>) -> AWResult<HttpResponse> {
+async fn fetch_model(
+    redis: web::Data<Addr<RedisActor>>,
+    info: web::Query<PublicResultRequestInfo>,
+) -> AWResult<HttpResponse> {
     info!("fetch_model query={:?}", info);
 
-    match info.result_type {
-        ResultType::Heightmap => Ok(HttpResponse::NotImplemented().finish()),
-        ResultType::Model => Ok(HttpResponse::NotImplemented().finish()),
+    let upload_id: UploadId = info.id.parse().map_err(|e| {
+        debug!("invalid upload id {:?}: {}", info.id, e);
+        actix_web::error::ErrorBadRequest("Invalid upload id")
+    })?;
+
+    let resp = redis
+        .send(Command(resp_array![
+            "HGET",
+            public_upload_key(upload_id),
+            info.result_type.field_name()
+        ]))
+        .await
+        .map_err(redis_error("HGET"))?
+        .map_err(redis_error("HGET"))?;
+
+    let response_data = convert_bytes_response(resp)?;
+
+    Ok(result_response_builder(&info.result_type, &upload_id).body(response_data))
+}
+
+#[post("/new")]
+async fn create_new(
+    session: Session,
+    redis: web::Data<Addr<RedisActor>>,
+    settings: web::Data<Settings>,
+    tokens: web::Data<JobTokens>,
+) -> AWResult<HttpResponse> {
+    info!("create_new");
+
+    session.clear();
+
+    let id = new_request_cookie(&session)?;
+    debug!("request-id: {}", id);
+    metrics::SESSIONS_CREATED.inc();
+
+    let resp = redis
+        .send(Command(resp_array![
+            "HSET",
+            request_key(id),
+            "created_at",
+            unix_timestamp().to_string(),
+            "state",
+            RequestState::New.as_str()
+        ]))
+        .await
+        .map_err(redis_error("HSET"))?
+        .map_err(redis_error("HSET"))?;
+
+    if let RespValue::Error(e) = resp {
+        error!("Redis error: {}", e);
+        return Ok(HttpResponse::InternalServerError().finish());
     }
+
+    expire(&redis, request_key(id), settings.limits.session_ttl).await?;
+
+    Ok(HttpResponse::Ok().json(NewRequestResponse {
+        request_id: id.to_string(),
+        token: tokens.issue(id),
+    }))
 }
 
+/// Prepares the heightmap for the given selection (or the whole image) and stores it
+async fn store_heightmap(
+    redis: &Addr<RedisActor>,
+    settings: &Settings,
+    id: RequestId,
+    data: Vec<u8>,
+    request_settings: Option<RequestSettings>,
+) -> AWResult<()> {
+    let selection = request_settings.clone();
+    let processed = actix_web::rt::task::spawn_blocking(move || {
+        let _timer = metrics::PREPARE_IMAGE_DURATION.start_timer();
+        run(&data, request_settings.as_ref())
+    })
+    .await
+    .unwrap()
+    .map_err(|e| {
+        error!("error processing image: {}", e);
+        metrics::PREPARE_IMAGE_FAILURES.inc();
+        if e.code == opencv::core::StsBadArg {
+            actix_web::error::ErrorBadRequest("Selected region is too small")
+        } else {
+            actix_web::error::ErrorInternalServerError("processing error")
+        }
+    })?;
+
+    let resp = redis
+        .send(Command(resp_array![
+            "SETEX",
+            processed_image_key(id),
+            settings.limits.image_ttl.to_string(),
+            processed.data.as_slice()
+        ]))
+        .await
+        .map_err(redis_error("SETEX"))?
+        .map_err(redis_error("SETEX"))?;
+
+    debug_assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+
+    let selection =
+        selection.unwrap_or_else(|| RequestSettings::full_image(processed.width, processed.height));
+
+    // Needed to validate the request settings in /start (and to detect a changed selection)
+    let resp = redis
+        .send(Command(resp_array![
+            "HSET",
+            request_key(id),
+            "width",
+            processed.width.to_string(),
+            "height",
+            processed.height.to_string(),
+            "processed_settings",
+            serde_json::to_string(&selection)?
+        ]))
+        .await
+        .map_err(redis_error("HSET"))?
+        .map_err(redis_error("HSET"))?;
+
+    if let RespValue::Error(e) = resp {
+        error!("Redis error: {}", e);
+        return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+    }
+
+    Ok(())
+}
 
 #[post("/upload")]
 async fn create_upload(
-    session: Session,
+    request: ActiveRequest,
     redis: web::Data<Addr<RedisActor>>,
     settings: web::Data<Settings>,
     mut payload: mp::Multipart,
 ) -> AWResult<HttpResponse> {
     info!("create_upload");
 
-    session.clear();
-
-    let id = new_request_cookie(&session)?;
+    let ActiveRequest(id) = request;
     debug!("request-id: {}", id);
 
+    request_state(&redis, id).await?;
+
+    let mut uploaded = false;
     while let Some(mut field) = payload.try_next().await? {
         let content_type = validate_mime_type(field.content_type())?;
         trace!("received image type: {:?}", content_type);
 
         let data = read_byte_chunks(&mut field).await?;
+        metrics::UPLOAD_SIZE.observe(data.len() as f64);
 
         let resp = redis
             .send(Command(resp_array![
@@ -65,96 +224,238 @@ async fn create_upload(
 
         debug_assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
 
-        let processed = actix_web::rt::task::spawn_blocking(move || run(&data))
-            .await
-            .unwrap()
-            .map_err(|e| {
-                error!("error processing image: {}", e);
-                actix_web::error::ErrorInternalServerError("processing error")
-            })?;
+        store_heightmap(&redis, &settings, id, data, None).await?;
 
-        let resp = redis
-            .send(Command(resp_array![
-                "SETEX",
-                processed_image_key(id),
-                settings.limits.image_ttl.to_string(),
-                processed.as_slice()
-            ]))
-            .await
-            .map_err(redis_error("SETEX"))?
-            .map_err(redis_error("SETEX"))?;
+        uploaded = true;
+    }
 
-        debug_assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
+    if !uploaded {
+        return Err(actix_web::error::ErrorBadRequest("No image uploaded"));
     }
 
+    set_request_state(
+        &redis,
+        id,
+        RequestState::Uploaded,
+        settings.limits.session_ttl,
+    )
+    .await?;
+
     Ok(HttpResponse::Ok().finish())
 }
 
+async fn uploaded_image_size(redis: &Addr<RedisActor>, id: RequestId) -> AWResult<(i32, i32)> {
+    let resp = redis
+        .send(Command(resp_array![
+            "HMGET",
+            request_key(id),
+            "width",
+            "height"
+        ]))
+        .await
+        .map_err(redis_error("HMGET"))?
+        .map_err(redis_error("HMGET"))?;
+
+    let parse_dimension = |resp: RespValue| -> AWResult<i32> {
+        convert_string_response(resp)?.parse().map_err(|e| {
+            error!("invalid image dimension for request {}: {}", id, e);
+            actix_web::error::ErrorInternalServerError("Invalid image size")
+        })
+    };
+
+    match resp {
+        RespValue::Array(values) if values.len() == 2 => {
+            let mut it = values.into_iter();
+            let width = parse_dimension(it.next().unwrap())?;
+            let height = parse_dimension(it.next().unwrap())?;
+            Ok((width, height))
+        }
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            Err(actix_web::error::ErrorInternalServerError("Redis error"))
+        }
+    }
+}
+
 #[post("/start")]
 async fn create_start(
-    session: Session,
+    request: ActiveRequest,
     redis: web::Data<Addr<RedisActor>>,
     settings: web::Data<Settings>,
+    body: web::Bytes,
 ) -> AWResult<HttpResponse> {
     info!("create_start");
 
-    let id = request_cookie(&session)?;
+    let ActiveRequest(id) = request;
     debug!("request-id: {}", id);
 
-    let resp1 = redis
+    if request_state(&redis, id).await? == RequestState::New {
+        return Err(actix_web::error::ErrorConflict("No image uploaded"));
+    }
+
+    let (width, height) = uploaded_image_size(&redis, id).await?;
+
+    let request_settings = if body.is_empty() {
+        RequestSettings::full_image(width, height)
+    } else {
+        serde_json::from_slice::<RequestSettings>(&body).map_err(|e| {
+            debug!("invalid request settings: {}", e);
+            actix_web::error::ErrorBadRequest(format!("Invalid request settings: {}", e))
+        })?
+    };
+    debug!("request settings: {:?}", request_settings);
+
+    request_settings.validate(width, height).map_err(|e| {
+        actix_web::error::ErrorBadRequest(format!("Invalid request settings: {}", e))
+    })?;
+
+    let resp = redis
         .send(Command(resp_array![
-            "XADD",
-            FABSEAL_SUBMISSION_QUEUE,
-            "MAXLEN",
-            "~",
-            settings.limits.queue_limit.to_string(),
-            "*",
-            "request_id",
-            &id.as_bytes()[..]
+            "HGET",
+            request_key(id),
+            "processed_settings"
         ]))
         .await
-        .map_err(redis_error("XADD"))?
-        .map_err(redis_error("XADD"))?;
-    match resp1 {
-        RespValue::Error(e) => {
-            error!("Redis error: {}", e);
-            Ok(HttpResponse::InternalServerError().finish())
-        }
-        RespValue::BulkString(_) => Ok(HttpResponse::Accepted().finish()),
-        _ => {
-            error!("Unexpected Redis response: {:?}", resp1);
-            Ok(HttpResponse::InternalServerError().finish())
+        .map_err(redis_error("HGET"))?
+        .map_err(redis_error("HGET"))?;
+
+    let processed_settings: Option<RequestSettings> = match resp {
+        RespValue::Nil => None,
+        resp => serde_json::from_str(&convert_string_response(resp)?).ok(),
+    };
+
+    let same_selection = processed_settings.map(|p| p.same_selection(&request_settings));
+    if same_selection != Some(true) {
+        debug!("selection changed, preparing image again");
+
+        let resp = redis
+            .send(Command(resp_array!["GET", image_key(id)]))
+            .await
+            .map_err(redis_error("GET"))?
+            .map_err(redis_error("GET"))?;
+
+        let data = convert_bytes_response(resp)?;
+
+        store_heightmap(&redis, &settings, id, data, Some(request_settings.clone())).await?;
+    }
+
+    let settings_json = serde_json::to_string(&request_settings)?;
+
+    let entry_id = match submit_job(
+        &redis,
+        id,
+        &settings_json,
+        settings.limits.queue_limit,
+        settings.limits.session_ttl,
+    )
+    .await?
+    {
+        Some(entry_id) => entry_id,
+        None => {
+            warn!("rejecting request {}: queue is full", id);
+            return Ok(HttpResponse::ServiceUnavailable()
+                .insert_header((
+                    header::RETRY_AFTER,
+                    QUEUE_FULL_RETRY_AFTER_SECONDS.to_string(),
+                ))
+                .body("Queue is full, please try again later"));
         }
+    };
+    debug!("entry-id: {}", entry_id);
+
+    set_request_state(
+        &redis,
+        id,
+        RequestState::Started,
+        settings.limits.session_ttl,
+    )
+    .await?;
+
+    // Not critical, the next submission trims again
+    if let Err(e) = trim_acknowledged(&redis).await {
+        warn!("error while trimming the submission queue: {}", e);
     }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[get("/status")]
+async fn create_status(
+    request: ActiveRequest,
+    redis: web::Data<Addr<RedisActor>>,
+) -> AWResult<HttpResponse> {
+    info!("create_status");
+
+    let ActiveRequest(id) = request;
+    debug!("request-id: {}", id);
+
+    let req_state = request_state(&redis, id).await?;
+
+    let resp = redis
+        .send(Command(resp_array!["HGETALL", status_key(id)]))
+        .await
+        .map_err(redis_error("HGETALL"))?
+        .map_err(redis_error("HGETALL"))?;
+
+    let mut status = convert_map_response(resp)?;
+
+    let state: JobState = match take_string_field(&mut status, "state")? {
+        Some(state) => state.parse().map_err(|e| {
+            error!("invalid job state for request {}: {}", id, e);
+            actix_web::error::ErrorInternalServerError("Invalid job state")
+        })?,
+        None => match req_state {
+            RequestState::New | RequestState::Uploaded => {
+                return Err(actix_web::error::ErrorNotFound("Job not started"));
+            }
+            // The status outlived its TTL
+            RequestState::Started | RequestState::Finished => JobState::Expired,
+        },
+    };
+
+    let state = if state == JobState::Done {
+        let resp = redis
+            .send(Command(resp_array!["EXISTS", result_key(id)]))
+            .await
+            .map_err(redis_error("EXISTS"))?
+            .map_err(redis_error("EXISTS"))?;
+        match resp {
+            RespValue::Integer(0) => JobState::Expired,
+            _ => JobState::Done,
+        }
+    } else {
+        state
+    };
+
+    let queue_position = match (state, take_string_field(&mut status, "entry_id")?) {
+        (JobState::Queued, Some(entry_id)) => queue_position(&redis, &entry_id).await?,
+        _ => None,
+    };
+
+    Ok(HttpResponse::Ok().json(JobStatusResponse {
+        state,
+        queue_position,
+        queued_at: take_integer_field(&mut status, "queued_at")?,
+        started_at: take_integer_field(&mut status, "started_at")?,
+        finished_at: take_integer_field(&mut status, "finished_at")?,
+        error: take_string_field(&mut status, "error")?,
+    }))
 }
 
 #[get("/result")]
 async fn create_result(
-    session: Session,
+    request: ActiveRequest,
     redis: web::Data<Addr<RedisActor>>,
     info: web::Query<ResultRequestInfo>,
 ) -> AWResult<HttpResponse> {
     info!("create_result query={:?}", info);
     // Ok(HttpResponse::Processing().finish())
 
-    let id = request_cookie(&session)?;
-
-    let mut response_builder = HttpResponse::Ok();
+    let ActiveRequest(id) = request;
 
     let key_function: fn(RequestId) -> String = match info.result_type {
-        ResultType::Heightmap => {
-            response_builder.content_type("image/jpeg");
-            processed_image_key
-        }
-        ResultType::Model => {
-            let cd = ContentDisposition {
-                disposition: DispositionType::Attachment,
-                parameters: vec![DispositionParam::Filename(format!("model_{}.stl", id))],
-            };
-            response_builder.append_header(cd);
-            response_builder.content_type("model/stl");
-            result_key
-        }
+        ResultType::Heightmap => processed_image_key,
+        ResultType::Model => result_key,
     };
 
     let resp = redis
@@ -165,22 +466,111 @@ async fn create_result(
 
     let response_data = convert_bytes_response(resp)?;
 
-    Ok(response_builder.body(response_data))
+    Ok(result_response_builder(&info.result_type, &id).body(response_data))
 }
 
+/// Publishes the result of a started request under a new upload id and marks the request as
+/// finished, or returns the upload id if the request was finished before
+///
+/// Checking the state and publishing happen in one script, so that finishing a request twice
+/// concurrently cannot publish it twice.
+///
+/// KEYS: request hash, result, processed image, public upload.
+/// ARGV: upload id, current time, TTL of the request in seconds, TTL of the public upload in
+/// seconds (0 to keep it), started state, finished state, model field, heightmap field.
+/// Returns the upload id, 'unknown', 'not_started' or 'not_available'.
+const FINISH_REQUEST_SCRIPT: &str = r#"
+local state = redis.call('HGET', KEYS[1], 'state')
+if not state then
+  return 'unknown'
+end
+if state == ARGV[6] then
+  return redis.call('HGET', KEYS[1], 'upload_id')
+end
+if state ~= ARGV[5] then
+  return 'not_started'
+end
+
+local model = redis.call('GET', KEYS[2])
+local heightmap = redis.call('GET', KEYS[3])
+if not model or not heightmap then
+  return 'not_available'
+end
+
+redis.call('HSET', KEYS[4], 'created_at', ARGV[2], ARGV[7], model, ARGV[8], heightmap)
+local upload_ttl = tonumber(ARGV[4])
+if upload_ttl > 0 then
+  redis.call('EXPIRE', KEYS[4], upload_ttl)
+end
+redis.call('HSET', KEYS[1], 'upload_id', ARGV[1], 'state', ARGV[6])
+redis.call('EXPIRE', KEYS[1], ARGV[3])
+return ARGV[1]
+"#;
+
 #[post("/finish")]
-async fn create_finish() -> AWResult<HttpResponse> {
+async fn create_finish(
+    request: ActiveRequest,
+    redis: web::Data<Addr<RedisActor>>,
+    settings: web::Data<Settings>,
+) -> AWResult<HttpResponse> {
     info!("create_finish");
 
-    Ok(HttpResponse::NotImplemented().finish())
+    let ActiveRequest(id) = request;
+    debug!("request-id: {}", id);
+
+    let upload_id = UploadId::new();
+    let resp = redis
+        .send(Command(resp_array![
+            "EVAL",
+            FINISH_REQUEST_SCRIPT,
+            "4",
+            request_key(id),
+            result_key(id),
+            processed_image_key(id),
+            public_upload_key(upload_id),
+            upload_id.to_string(),
+            unix_timestamp().to_string(),
+            settings.limits.session_ttl.to_string(),
+            settings.limits.public_upload_ttl.unwrap_or(0).to_string(),
+            RequestState::Started.as_str(),
+            RequestState::Finished.as_str(),
+            ResultType::Model.field_name(),
+            ResultType::Heightmap.field_name()
+        ]))
+        .await
+        .map_err(redis_error("EVAL"))?
+        .map_err(redis_error("EVAL"))?;
+
+    let upload_id = match convert_string_response(resp)?.as_str() {
+        "unknown" => {
+            info!("unknown (or expired) request {}", id);
+            return Err(actix_web::error::ErrorNotFound("Unknown request"));
+        }
+        "not_started" => {
+            return Err(actix_web::error::ErrorConflict("Request was not started"));
+        }
+        "not_available" => {
+            return Err(actix_web::error::ErrorConflict(
+                "Result not available (not finished yet or expired)",
+            ));
+        }
+        upload_id => upload_id.to_string(),
+    };
+    debug!("upload-id: {}", upload_id);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain")
+        .body(format!("id={}", upload_id)))
 }
 
 pub(crate) fn create_service(cfg: &mut web::ServiceConfig) {
     cfg.service(web::scope("/userupload").service(fetch_model));
     cfg.service(
         web::scope("/create")
+            .service(create_new)
             .service(create_upload)
             .service(create_start)
+            .service(create_status)
             .service(create_result)
             .service(create_finish),
     );
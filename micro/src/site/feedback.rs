@@ -0,0 +1,678 @@
+use actix::Addr;
+use actix_multipart as mp;
+use actix_redis::{Command, RespValue};
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective, ETag, EntityTag, Header, IfNoneMatch},
+    post, web, HttpRequest, HttpResponse, HttpResponseBuilder, Result as AWResult,
+};
+
+use futures_util::TryStreamExt;
+
+use log::{debug, error, info, trace, warn};
+
+use sha2::{Digest, Sha256};
+
+use fabseal_micro_common::*;
+use redis_async::resp_array;
+
+use crate::{
+    metrics,
+    redis_connection::RedisActor,
+    settings::Settings,
+    site::{types::*, util::*},
+};
+
+/// Dataset names and image ids are part of Redis keys, so they are restricted
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_FEEDBACK_IMAGES: usize = 8;
+const DEFAULT_FEEDBACK_PAGE_SIZE: u64 = 20;
+const MAX_FEEDBACK_PAGE_SIZE: u64 = 100;
+/// Published feedback images never change
+const FEEDBACK_IMAGE_MAX_AGE_SECONDS: u32 = 60 * 60 * 24 * 365;
+
+fn validate_name(name: &str, what: &str) -> AWResult<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.');
+    if valid {
+        Ok(())
+    } else {
+        debug!("invalid {} {:?}", what, name);
+        Err(actix_web::error::ErrorBadRequest(format!(
+            "Invalid {}",
+            what
+        )))
+    }
+}
+
+impl FeedbackPath {
+    pub(crate) fn validate(&self) -> AWResult<()> {
+        validate_name(&self.dataset_name, "dataset name")?;
+        validate_name(&self.image_id, "image id")
+    }
+}
+
+/// Reads string fields of a hash, `None` for missing fields
+async fn hmget_strings(
+    redis: &Addr<RedisActor>,
+    key: String,
+    fields: &[&str],
+) -> AWResult<Vec<Option<String>>> {
+    let mut command = vec![RespValue::from("HMGET"), RespValue::from(key)];
+    command.extend(fields.iter().map(|&field| RespValue::from(field)));
+
+    let resp = redis
+        .send(Command(RespValue::Array(command)))
+        .await
+        .map_err(redis_error("HMGET"))?
+        .map_err(redis_error("HMGET"))?;
+
+    match resp {
+        RespValue::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                RespValue::Nil => Ok(None),
+                value => convert_string_response(value).map(Some),
+            })
+            .collect(),
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            Err(actix_web::error::ErrorInternalServerError("Redis error"))
+        }
+    }
+}
+
+/// Checks that a feedback request belongs to the dataset image, it is treated as unknown otherwise.
+/// Shared by the scripts below, which work on the request hash in `KEYS[1]` with the dataset
+/// name and image id in `ARGV[1]` and `ARGV[2]`.
+macro_rules! feedback_request_script {
+    ($body:literal) => {
+        concat!(
+            r#"
+if redis.call('HGET', KEYS[1], 'dataset_name') ~= ARGV[1]
+    or redis.call('HGET', KEYS[1], 'image_id') ~= ARGV[2] then
+  return 'unknown'
+end
+local feedback_id = redis.call('HGET', KEYS[1], 'feedback_id')
+"#,
+            $body
+        )
+    };
+}
+
+/// Stores the metadata of a feedback request that has not been finished yet
+///
+/// ARGV[3]: metadata (JSON). Returns `ok`, `unknown` or `finished`.
+const SET_METADATA_SCRIPT: &str = feedback_request_script!(
+    r#"
+if feedback_id then
+  return 'finished'
+end
+redis.call('HSET', KEYS[1], 'metadata', ARGV[3])
+return 'ok'
+"#
+);
+
+/// Attaches an image to a feedback request that has not been finished yet
+///
+/// ARGV[3]: maximum number of images, ARGV[4]: image data, ARGV[5]: content type.
+/// Returns `ok`, `unknown`, `finished` or `too_many_images`.
+const ADD_IMAGE_SCRIPT: &str = feedback_request_script!(
+    r#"
+if feedback_id then
+  return 'finished'
+end
+local index = tonumber(redis.call('HGET', KEYS[1], 'images') or '0')
+if index >= tonumber(ARGV[3]) then
+  return 'too_many_images'
+end
+redis.call('HSET', KEYS[1], 'image_' .. index, ARGV[4], 'content_type_' .. index, ARGV[5],
+  'images', index + 1)
+return 'ok'
+"#
+);
+
+/// Publishes a feedback request: stores the feedback and its images, adds it to the index
+/// of the dataset image and marks the request as finished (keeping it until it expires)
+///
+/// KEYS[2]: feedback, KEYS[3]: feedback index, KEYS[4..]: one per image (as many as allowed).
+/// ARGV[3]: feedback id, ARGV[4]: current time, ARGV[5..]: image ids for the keys from KEYS[4].
+/// Returns the feedback id (also if the request was finished before), `unknown` or `no_metadata`.
+const FINISH_SCRIPT: &str = feedback_request_script!(
+    r#"
+if feedback_id then
+  return feedback_id
+end
+local metadata = redis.call('HGET', KEYS[1], 'metadata')
+if not metadata then
+  return 'no_metadata'
+end
+
+local image_ids = {}
+local count = tonumber(redis.call('HGET', KEYS[1], 'images') or '0')
+for index = 0, count - 1 do
+  local data = redis.call('HGET', KEYS[1], 'image_' .. index)
+  local content_type = redis.call('HGET', KEYS[1], 'content_type_' .. index)
+  local n = #image_ids + 1
+  redis.call('HSET', KEYS[3 + n], 'feedback_id', ARGV[3], 'content_type', content_type,
+    'data', data)
+  image_ids[n] = '"' .. ARGV[4 + n] .. '"'
+end
+
+redis.call('HSET', KEYS[2], 'created_at', ARGV[4], 'dataset_name', ARGV[1],
+  'image_id', ARGV[2], 'metadata', metadata, 'images', '[' .. table.concat(image_ids, ',') .. ']')
+redis.call('ZADD', KEYS[3], ARGV[4], ARGV[3])
+redis.call('HSET', KEYS[1], 'feedback_id', ARGV[3])
+return ARGV[3]
+"#
+);
+
+/// Parses the id of a feedback request for the dataset image in `path`
+fn feedback_request_id(path: &FeedbackPath, info: &FeedbackRequestInfo) -> AWResult<RequestId> {
+    path.validate()?;
+    info.id.parse().map_err(|e| {
+        debug!("invalid request id {:?}: {}", info.id, e);
+        actix_web::error::ErrorBadRequest("Invalid request id")
+    })
+}
+
+/// Runs one of the scripts on a feedback request, returns its result
+async fn eval_feedback_script(
+    redis: &Addr<RedisActor>,
+    script: &str,
+    keys: Vec<String>,
+    args: Vec<RespValue>,
+) -> AWResult<String> {
+    let mut command = vec![
+        RespValue::from("EVAL"),
+        RespValue::from(script),
+        RespValue::from(keys.len().to_string()),
+    ];
+    command.extend(keys.into_iter().map(RespValue::from));
+    command.extend(args);
+
+    let resp = redis
+        .send(Command(RespValue::Array(command)))
+        .await
+        .map_err(redis_error("EVAL"))?
+        .map_err(redis_error("EVAL"))?;
+
+    match resp {
+        RespValue::Error(e) => {
+            error!("Redis error: {}", e);
+            Err(actix_web::error::ErrorInternalServerError("Redis error"))
+        }
+        resp => convert_string_response(resp),
+    }
+}
+
+/// Maps the results shared by the scripts to errors
+fn check_script_result(id: RequestId, result: &str) -> AWResult<()> {
+    match result {
+        "unknown" => {
+            // Requests for other dataset images are treated like unknown requests
+            info!("unknown (or expired) feedback request {}", id);
+            Err(actix_web::error::ErrorNotFound("Unknown feedback request"))
+        }
+        "finished" => Err(actix_web::error::ErrorConflict(
+            "Feedback was already submitted",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn id_response(id: &dyn std::fmt::Display) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain")
+        .body(format!("id={}", id))
+}
+
+#[post("/{dataset_name}/{image_id}/new")]
+async fn feedback_new(
+    path: web::Path<FeedbackPath>,
+    redis: web::Data<Addr<RedisActor>>,
+    settings: web::Data<Settings>,
+) -> AWResult<HttpResponse> {
+    info!("feedback_new path={:?}", path);
+
+    path.validate()?;
+
+    let id = RequestId::new();
+    debug!("request-id: {}", id);
+
+    let resp = redis
+        .send(Command(resp_array![
+            "HSET",
+            feedback_request_key(id),
+            "created_at",
+            unix_timestamp().to_string(),
+            "dataset_name",
+            path.dataset_name.as_str(),
+            "image_id",
+            path.image_id.as_str(),
+            "images",
+            "0"
+        ]))
+        .await
+        .map_err(redis_error("HSET"))?
+        .map_err(redis_error("HSET"))?;
+
+    if let RespValue::Error(e) = resp {
+        error!("Redis error: {}", e);
+        return Ok(HttpResponse::InternalServerError().finish());
+    }
+
+    expire(
+        &redis,
+        feedback_request_key(id),
+        settings.limits.session_ttl,
+    )
+    .await?;
+
+    Ok(id_response(&id))
+}
+
+#[post("/{dataset_name}/{image_id}/metadata")]
+async fn feedback_metadata(
+    path: web::Path<FeedbackPath>,
+    info: web::Query<FeedbackRequestInfo>,
+    redis: web::Data<Addr<RedisActor>>,
+    metadata: web::Json<serde_json::Value>,
+) -> AWResult<HttpResponse> {
+    info!("feedback_metadata path={:?} query={:?}", path, info);
+
+    let id = feedback_request_id(&path, &info)?;
+
+    if !metadata.is_object() {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Metadata has to be a JSON object",
+        ));
+    }
+
+    let result = eval_feedback_script(
+        &redis,
+        SET_METADATA_SCRIPT,
+        vec![feedback_request_key(id)],
+        vec![
+            RespValue::from(path.dataset_name.as_str()),
+            RespValue::from(path.image_id.as_str()),
+            RespValue::from(serde_json::to_string(&metadata.into_inner())?),
+        ],
+    )
+    .await?;
+    check_script_result(id, &result)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/{dataset_name}/{image_id}/upload")]
+async fn feedback_upload(
+    path: web::Path<FeedbackPath>,
+    info: web::Query<FeedbackRequestInfo>,
+    redis: web::Data<Addr<RedisActor>>,
+    mut payload: mp::Multipart,
+) -> AWResult<HttpResponse> {
+    info!("feedback_upload path={:?} query={:?}", path, info);
+
+    let id = feedback_request_id(&path, &info)?;
+
+    let mut uploaded = false;
+    while let Some(mut field) = payload.try_next().await? {
+        let image_type = validate_mime_type(field.content_type())?;
+        trace!("received image type: {:?}", image_type);
+
+        let data = read_byte_chunks(&mut field).await?;
+        metrics::UPLOAD_SIZE.observe(data.len() as f64);
+
+        // Refused once the request is finished, so that no image is left out of the feedback
+        let result = eval_feedback_script(
+            &redis,
+            ADD_IMAGE_SCRIPT,
+            vec![feedback_request_key(id)],
+            vec![
+                RespValue::from(path.dataset_name.as_str()),
+                RespValue::from(path.image_id.as_str()),
+                RespValue::from(MAX_FEEDBACK_IMAGES.to_string()),
+                RespValue::from(data),
+                RespValue::from(image_type.content_type()),
+            ],
+        )
+        .await?;
+        check_script_result(id, &result)?;
+        if result == "too_many_images" {
+            return Err(actix_web::error::ErrorBadRequest(format!(
+                "At most {} images can be attached",
+                MAX_FEEDBACK_IMAGES
+            )));
+        }
+
+        uploaded = true;
+    }
+
+    if !uploaded {
+        return Err(actix_web::error::ErrorBadRequest("No image uploaded"));
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/{dataset_name}/{image_id}/finish")]
+async fn feedback_finish(
+    path: web::Path<FeedbackPath>,
+    info: web::Query<FeedbackRequestInfo>,
+    redis: web::Data<Addr<RedisActor>>,
+) -> AWResult<HttpResponse> {
+    info!("feedback_finish path={:?} query={:?}", path, info);
+
+    let id = feedback_request_id(&path, &info)?;
+
+    let feedback_id = FeedbackId::new();
+    let image_ids: Vec<FeedbackImageId> = (0..MAX_FEEDBACK_IMAGES)
+        .map(|_| FeedbackImageId::new())
+        .collect();
+
+    let mut keys = vec![
+        feedback_request_key(id),
+        feedback_key(feedback_id),
+        feedback_index_key(&path.dataset_name, &path.image_id),
+    ];
+    keys.extend(
+        image_ids
+            .iter()
+            .map(|&image_id| feedback_image_key(image_id)),
+    );
+
+    let mut args = vec![
+        RespValue::from(path.dataset_name.as_str()),
+        RespValue::from(path.image_id.as_str()),
+        RespValue::from(feedback_id.to_string()),
+        RespValue::from(unix_timestamp().to_string()),
+    ];
+    args.extend(
+        image_ids
+            .iter()
+            .map(|image_id| RespValue::from(image_id.to_string())),
+    );
+
+    // Finishing a request twice returns the id of the feedback published the first time
+    let result = eval_feedback_script(&redis, FINISH_SCRIPT, keys, args).await?;
+    check_script_result(id, &result)?;
+    if result == "no_metadata" {
+        return Err(actix_web::error::ErrorConflict("No metadata submitted"));
+    }
+    debug!("feedback-id: {}", result);
+
+    Ok(id_response(&result))
+}
+
+/// Whether the `If-None-Match` header of the request matches `etag`
+fn etag_matches(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
+
+/// `304 Not Modified` if the client has the current version, `200 OK` otherwise
+fn conditional_response(req: &HttpRequest, etag: EntityTag) -> (HttpResponseBuilder, bool) {
+    let not_modified = etag_matches(req, &etag);
+    let mut response_builder = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response_builder.insert_header(ETag(etag));
+    (response_builder, not_modified)
+}
+
+fn feedback_image_url(path: &FeedbackPath, feedback_id: &str, image_id: &str) -> String {
+    format!(
+        "/api/v1/feedback/{}/{}/{}/{}",
+        path.dataset_name, path.image_id, feedback_id, image_id
+    )
+}
+
+/// Reads published feedback, `None` if it does not exist
+async fn fetch_feedback(
+    redis: &Addr<RedisActor>,
+    path: &FeedbackPath,
+    feedback_id: FeedbackId,
+) -> AWResult<Option<FeedbackResponse>> {
+    let mut fields = hmget_strings(
+        redis,
+        feedback_key(feedback_id),
+        &["created_at", "metadata", "images"],
+    )
+    .await?
+    .into_iter();
+    let created_at = fields.next().flatten();
+    let metadata = match fields.next().flatten() {
+        Some(metadata) => metadata,
+        None => return Ok(None),
+    };
+    let images = fields.next().flatten();
+
+    let invalid = |e: serde_json::Error| {
+        error!("invalid feedback {}: {}", feedback_id, e);
+        actix_web::error::ErrorInternalServerError("Invalid feedback")
+    };
+    let metadata = serde_json::from_str(&metadata).map_err(invalid)?;
+    let images: Vec<String> = match images {
+        Some(images) => serde_json::from_str(&images).map_err(invalid)?,
+        None => vec![],
+    };
+
+    let feedback_id = feedback_id.to_string();
+    Ok(Some(FeedbackResponse {
+        created_at: created_at.and_then(|t| t.parse().ok()),
+        metadata,
+        images: images
+            .into_iter()
+            .map(|id| FeedbackImageResponse {
+                url: feedback_image_url(path, &feedback_id, &id),
+                id,
+            })
+            .collect(),
+        id: feedback_id,
+    }))
+}
+
+#[get("/{dataset_name}/{image_id}")]
+async fn feedback_list(
+    req: HttpRequest,
+    path: web::Path<FeedbackPath>,
+    info: web::Query<FeedbackListInfo>,
+    redis: web::Data<Addr<RedisActor>>,
+) -> AWResult<HttpResponse> {
+    info!("feedback_list path={:?} query={:?}", path, info);
+
+    path.validate()?;
+    let limit = info
+        .limit
+        .unwrap_or(DEFAULT_FEEDBACK_PAGE_SIZE)
+        .clamp(1, MAX_FEEDBACK_PAGE_SIZE);
+    let index_key = feedback_index_key(&path.dataset_name, &path.image_id);
+
+    let resp = redis
+        .send(Command(resp_array!["ZCARD", index_key.as_str()]))
+        .await
+        .map_err(redis_error("ZCARD"))?
+        .map_err(redis_error("ZCARD"))?;
+    let total = match resp {
+        RespValue::Integer(total) => total.max(0) as u64,
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+        }
+    };
+
+    // Pages past the end are empty. Redis rejects indices that do not fit into an i64,
+    // but indices below the size of the index always do.
+    let ids = if info.offset < total {
+        let resp = redis
+            .send(Command(resp_array![
+                "ZREVRANGE",
+                index_key.as_str(),
+                info.offset.to_string(),
+                (info.offset + (limit - 1)).min(total - 1).to_string()
+            ]))
+            .await
+            .map_err(redis_error("ZREVRANGE"))?
+            .map_err(redis_error("ZREVRANGE"))?;
+        match resp {
+            RespValue::Array(ids) => ids,
+            _ => {
+                error!("Unexpected Redis response: {:?}", resp);
+                return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+            }
+        }
+    } else {
+        vec![]
+    };
+
+    let mut feedback = Vec::with_capacity(ids.len());
+    for id in ids {
+        let id = convert_string_response(id)?;
+        let feedback_id: FeedbackId = id.parse().map_err(|e| {
+            error!("invalid feedback id {:?} in {}: {}", id, index_key, e);
+            actix_web::error::ErrorInternalServerError("Invalid feedback")
+        })?;
+        match fetch_feedback(&redis, &path, feedback_id).await? {
+            Some(entry) => feedback.push(entry),
+            None => warn!("feedback {} is listed in {}, but missing", id, index_key),
+        }
+    }
+
+    let body = serde_json::to_vec(&FeedbackListResponse {
+        total,
+        offset: info.offset,
+        limit,
+        feedback,
+    })?;
+    let digest = Sha256::digest(&body);
+    let etag = EntityTag::strong(base64::encode_config(
+        &digest[..16],
+        base64::URL_SAFE_NO_PAD,
+    ));
+
+    // New feedback can be posted at any time, so clients have to revalidate
+    let (mut response_builder, not_modified) = conditional_response(&req, etag);
+    response_builder.insert_header(CacheControl(vec![CacheDirective::NoCache]));
+    if not_modified {
+        return Ok(response_builder.finish());
+    }
+    Ok(response_builder.content_type("application/json").body(body))
+}
+
+/// Responds with a feedback image, if `path` is given the image has to belong to that feedback
+async fn feedback_image(
+    req: &HttpRequest,
+    redis: &Addr<RedisActor>,
+    image_id: &str,
+    path: Option<&FeedbackImagePath>,
+) -> AWResult<HttpResponse> {
+    let not_found = || actix_web::error::ErrorNotFound("Unknown feedback image");
+
+    let image_id: FeedbackImageId = image_id.parse().map_err(|e| {
+        debug!("invalid feedback image id {:?}: {}", image_id, e);
+        not_found()
+    })?;
+
+    let mut fields = hmget_strings(
+        redis,
+        feedback_image_key(image_id),
+        &["feedback_id", "content_type"],
+    )
+    .await?
+    .into_iter();
+    let (feedback_id, content_type) = match (fields.next().flatten(), fields.next().flatten()) {
+        (Some(feedback_id), Some(content_type)) => (feedback_id, content_type),
+        _ => return Err(not_found()),
+    };
+
+    if let Some(path) = path {
+        let feedback: FeedbackId = path.feedback_id.parse().map_err(|e| {
+            debug!("invalid feedback id {:?}: {}", path.feedback_id, e);
+            not_found()
+        })?;
+        if feedback_id != path.feedback_id {
+            return Err(not_found());
+        }
+
+        let mut fields =
+            hmget_strings(redis, feedback_key(feedback), &["dataset_name", "image_id"])
+                .await?
+                .into_iter();
+        if fields.next().flatten().as_deref() != Some(path.dataset_name.as_str())
+            || fields.next().flatten().as_deref() != Some(path.image_id.as_str())
+        {
+            return Err(not_found());
+        }
+    }
+
+    let (mut response_builder, not_modified) =
+        conditional_response(req, EntityTag::strong(image_id.to_string()));
+    response_builder.insert_header(CacheControl(vec![
+        CacheDirective::Public,
+        CacheDirective::MaxAge(FEEDBACK_IMAGE_MAX_AGE_SECONDS),
+    ]));
+    if not_modified {
+        return Ok(response_builder.finish());
+    }
+
+    let resp = redis
+        .send(Command(resp_array![
+            "HGET",
+            feedback_image_key(image_id),
+            "data"
+        ]))
+        .await
+        .map_err(redis_error("HGET"))?
+        .map_err(redis_error("HGET"))?;
+    let data = convert_bytes_response(resp)?;
+
+    Ok(response_builder.content_type(content_type).body(data))
+}
+
+#[get("/{dataset_name}/{image_id}/{feedback_id}/{feedback_image_id}")]
+async fn feedback_image_by_feedback(
+    req: HttpRequest,
+    path: web::Path<FeedbackImagePath>,
+    redis: web::Data<Addr<RedisActor>>,
+) -> AWResult<HttpResponse> {
+    info!("feedback_image path={:?}", path);
+
+    feedback_image(&req, &redis, &path.feedback_image_id, Some(&path)).await
+}
+
+#[get("/images/{feedback_image_id}.jpg")]
+async fn feedback_image_by_id(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    redis: web::Data<Addr<RedisActor>>,
+) -> AWResult<HttpResponse> {
+    info!("feedback_image path={:?}", path);
+
+    feedback_image(&req, &redis, &path.0, None).await
+}
+
+pub(crate) fn feedback_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/feedback")
+            .service(feedback_new)
+            .service(feedback_metadata)
+            .service(feedback_upload)
+            .service(feedback_finish)
+            .service(feedback_list)
+            .service(feedback_image_by_feedback),
+    );
+}
+
+/// Feedback images by id only (`/data/feedback/images/<feedback_image_id>.jpg`)
+pub(crate) fn feedback_data_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/data/feedback").service(feedback_image_by_id));
+}
@@ -0,0 +1,125 @@
+use actix::Addr;
+use actix_redis::{Command, RespValue};
+use actix_web::{get, web, HttpResponse, Result as AWResult};
+
+use log::{error, info};
+
+use fabseal_micro_common::*;
+use redis_async::resp_array;
+
+use crate::{
+    redis_connection::RedisActor,
+    site::{queue::submission_group_info, types::*, util::*},
+};
+
+async fn check_redis(redis: &Addr<RedisActor>) -> CheckResult {
+    let resp = redis
+        .send(Command(resp_array!["PING"]))
+        .await
+        .map_err(redis_error("PING"))
+        .and_then(|resp| resp.map_err(redis_error("PING")));
+
+    match resp {
+        Ok(RespValue::SimpleString(s)) if s == "PONG" => CheckResult::ok(None),
+        Ok(resp) => {
+            error!("Unexpected Redis response: {:?}", resp);
+            CheckResult::failed("Unexpected response to PING")
+        }
+        Err(_) => CheckResult::failed("Redis is not reachable"),
+    }
+}
+
+async fn check_queue(redis: &Addr<RedisActor>) -> CheckResult {
+    match submission_group_info(redis).await {
+        Ok(Some(info)) => CheckResult::ok(Some(format!("{} consumers", info.consumers))),
+        Ok(None) => CheckResult::failed("Submission stream or consumer group does not exist"),
+        Err(_) => CheckResult::failed("Redis error"),
+    }
+}
+
+/// Number of workers whose last heartbeat is recent
+async fn alive_workers(redis: &Addr<RedisActor>) -> AWResult<usize> {
+    let resp = redis
+        .send(Command(resp_array!["SMEMBERS", worker_registry_key()]))
+        .await
+        .map_err(redis_error("SMEMBERS"))?
+        .map_err(redis_error("SMEMBERS"))?;
+
+    let names = match resp {
+        RespValue::Array(names) => names,
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+        }
+    };
+
+    let min_last_seen = unix_timestamp().saturating_sub(WORKER_HEARTBEAT_TTL_SECONDS);
+    let mut alive = 0;
+    for name in names {
+        let name = convert_string_response(name)?;
+        let resp = redis
+            .send(Command(resp_array!["HGET", worker_key(&name), "last_seen"]))
+            .await
+            .map_err(redis_error("HGET"))?
+            .map_err(redis_error("HGET"))?;
+
+        let last_seen: Option<u64> = match resp {
+            RespValue::Nil => None,
+            resp => convert_string_response(resp)?.parse().ok(),
+        };
+        if matches!(last_seen, Some(t) if t >= min_last_seen) {
+            alive += 1;
+        }
+    }
+
+    Ok(alive)
+}
+
+async fn check_workers(redis: &Addr<RedisActor>) -> CheckResult {
+    match alive_workers(redis).await {
+        Ok(0) => CheckResult::failed("No worker heartbeat"),
+        Ok(alive) => CheckResult::ok(Some(format!("{} workers alive", alive))),
+        Err(_) => CheckResult::failed("Redis error"),
+    }
+}
+
+/// Liveness: the HTTP server is running
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(HealthResponse { status: "ok" })
+}
+
+/// Readiness: Redis is reachable, the submission queue exists and at least one worker is alive
+#[get("/readyz")]
+async fn readyz(redis: web::Data<Addr<RedisActor>>) -> HttpResponse {
+    let redis_check = check_redis(&redis).await;
+    let checks = if redis_check.ok {
+        ReadinessChecks {
+            redis: redis_check,
+            queue: check_queue(&redis).await,
+            workers: check_workers(&redis).await,
+        }
+    } else {
+        ReadinessChecks {
+            redis: redis_check,
+            queue: CheckResult::failed("Redis is not reachable"),
+            workers: CheckResult::failed("Redis is not reachable"),
+        }
+    };
+
+    let ready = checks.redis.ok && checks.queue.ok && checks.workers.ok;
+    if !ready {
+        info!("not ready: {:?}", checks);
+    }
+
+    let mut response = if ready {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    response.json(ReadinessResponse { ready, checks })
+}
+
+pub(crate) fn health_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz).service(readyz);
+}
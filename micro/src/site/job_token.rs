@@ -0,0 +1,243 @@
+use actix_session::UserSession;
+use actix_web::{
+    dev::Payload,
+    http::{header, HeaderMap},
+    web, FromRequest, HttpRequest, Result as AWResult,
+};
+
+use futures_util::future::{ready, Ready};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use log::{debug, warn};
+
+use fabseal_micro_common::{unix_timestamp, RequestId};
+
+use crate::{session::SessionKeys, site::util::request_cookie};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const JOB_TOKEN_VERSION: &str = "v1";
+/// Same as the lifetime of the session cookie
+const JOB_TOKEN_LIFETIME_SECONDS: u64 = 60 * 60;
+
+/// Issues and verifies job tokens, which identify a create request without a session cookie
+///
+/// A token is `v1.<request id>.<expiry time>.<signature>`, signed with HMAC-SHA256 using the
+/// signing part of the session key. Tokens signed with a previous session key are still accepted.
+/// Tokens are stateless: revoking the session that created the request does not revoke its token,
+/// they are only invalidated by expiry or by removing the signing key from the session keys.
+pub(crate) struct JobTokens {
+    current: Vec<u8>,
+    previous: Vec<Vec<u8>>,
+}
+
+fn sign(key: &[u8], payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+impl JobTokens {
+    pub(crate) fn new(keys: &SessionKeys) -> Self {
+        let mut signing_keys = keys.signing_keys();
+        let current = signing_keys.remove(0);
+        JobTokens {
+            current,
+            previous: signing_keys,
+        }
+    }
+
+    pub(crate) fn issue(&self, id: RequestId) -> String {
+        let payload = format!(
+            "{}.{}.{}",
+            JOB_TOKEN_VERSION,
+            id,
+            unix_timestamp() + JOB_TOKEN_LIFETIME_SECONDS
+        );
+        let signature = sign(&self.current, &payload).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Returns the request id of a valid token that has not expired
+    pub(crate) fn verify(&self, token: &str) -> Option<RequestId> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        let signed = std::iter::once(&self.current)
+            .chain(&self.previous)
+            .any(|key| sign(key, payload).verify(&signature).is_ok());
+        if !signed {
+            return None;
+        }
+
+        let mut parts = payload.split('.');
+        if parts.next()? != JOB_TOKEN_VERSION {
+            return None;
+        }
+        let id = parts.next()?.parse().ok()?;
+        let expires_at: u64 = parts.next()?.parse().ok()?;
+        if parts.next().is_some() || expires_at < unix_timestamp() {
+            return None;
+        }
+
+        Some(id)
+    }
+
+    /// Request id of a valid token in the `Authorization` header
+    pub(crate) fn verify_header(&self, headers: &HeaderMap) -> Option<RequestId> {
+        self.verify(bearer_token(headers)?)
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Extractor for the create request of the client, identified by a job token
+/// (`Authorization: Bearer <token>`) or by the session cookie
+pub(crate) struct ActiveRequest(pub(crate) RequestId);
+
+fn active_request(req: &HttpRequest) -> AWResult<ActiveRequest> {
+    if bearer_token(req.headers()).is_none() {
+        return request_cookie(&req.get_session()).map(ActiveRequest);
+    }
+
+    let tokens = req.app_data::<web::Data<JobTokens>>();
+    match tokens.and_then(|tokens| tokens.verify_header(req.headers())) {
+        Some(id) => {
+            debug!("request-id {} from job token", id);
+            Ok(ActiveRequest(id))
+        }
+        None => {
+            warn!("invalid job token for {}", req.path());
+            Err(actix_web::error::ErrorUnauthorized(
+                "Invalid or expired job token",
+            ))
+        }
+    }
+}
+
+impl FromRequest for ActiveRequest {
+    type Config = ();
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(active_request(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens() -> JobTokens {
+        JobTokens {
+            current: b"current key".to_vec(),
+            previous: vec![b"previous key".to_vec()],
+        }
+    }
+
+    /// Token for an arbitrary payload, signed with `key`
+    fn token(key: &[u8], payload: &str) -> String {
+        let signature = sign(key, payload).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    fn expiry() -> u64 {
+        unix_timestamp() + JOB_TOKEN_LIFETIME_SECONDS
+    }
+
+    fn verified(tokens: &JobTokens, token: &str) -> Option<String> {
+        tokens.verify(token).map(|id| id.to_string())
+    }
+
+    #[test]
+    fn verifies_issued_token() {
+        let tokens = tokens();
+        let id = RequestId::new();
+        assert_eq!(verified(&tokens, &tokens.issue(id)), Some(id.to_string()));
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let id = RequestId::new();
+        let payload = format!("v1.{}.{}", id, unix_timestamp() - 1);
+        assert_eq!(verified(&tokens(), &token(b"current key", &payload)), None);
+    }
+
+    #[test]
+    fn rejects_tampered_signature() {
+        let tokens = tokens();
+        let token = tokens.issue(RequestId::new());
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+        let tampered = if signature.starts_with('A') { 'B' } else { 'A' };
+        let token = format!("{}.{}{}", payload, tampered, &signature[1..]);
+        assert_eq!(verified(&tokens, &token), None);
+
+        assert_eq!(verified(&tokens, payload), None);
+    }
+
+    #[test]
+    fn rejects_signature_of_another_request() {
+        let tokens = tokens();
+        let token = tokens.issue(RequestId::new());
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+        let mut parts: Vec<&str> = payload.split('.').collect();
+        let other = RequestId::new().to_string();
+        parts[1] = &other;
+        let token = format!("{}.{}", parts.join("."), signature);
+        assert_eq!(verified(&tokens, &token), None);
+    }
+
+    #[test]
+    fn rejects_unknown_key() {
+        let payload = format!("v1.{}.{}", RequestId::new(), expiry());
+        assert_eq!(verified(&tokens(), &token(b"other key", &payload)), None);
+    }
+
+    #[test]
+    fn accepts_previous_key() {
+        let id = RequestId::new();
+        let payload = format!("v1.{}.{}", id, expiry());
+        assert_eq!(
+            verified(&tokens(), &token(b"previous key", &payload)),
+            Some(id.to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_payload() {
+        let id = RequestId::new();
+        for payload in &[
+            format!("v2.{}.{}", id, expiry()),
+            format!("{}.{}", id, expiry()),
+            format!("v1.{}", id),
+            format!("v1.{}.{}.0", id, expiry()),
+            format!("v1.not-an-id.{}", expiry()),
+            format!("v1.{}.soon", id),
+            String::new(),
+        ] {
+            assert_eq!(
+                verified(&tokens(), &token(b"current key", payload)),
+                None,
+                "{:?}",
+                payload
+            );
+        }
+        assert_eq!(verified(&tokens(), ""), None);
+        assert_eq!(verified(&tokens(), "v1"), None);
+    }
+}
@@ -1,4 +1,9 @@
+pub(crate) mod admin;
 pub mod create;
+pub(crate) mod feedback;
+pub(crate) mod health;
+pub(crate) mod job_token;
 
+pub(crate) mod queue;
 pub(crate) mod types;
 pub(crate) mod util;
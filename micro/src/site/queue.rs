@@ -0,0 +1,433 @@
+use actix::Addr;
+use actix_redis::{Command, RespValue};
+use actix_web::Result as AWResult;
+
+use log::{debug, error};
+
+use fabseal_micro_common::{
+    status_key, unix_timestamp, JobState, RequestId, FABSEAL_SUBMISSION_CONSUMER_GROUP,
+    FABSEAL_SUBMISSION_QUEUE,
+};
+use redis_async::resp_array;
+
+use crate::{redis_connection::RedisActor, site::util::*};
+
+/// State of the workers' consumer group on the submission stream
+#[derive(Debug)]
+pub(crate) struct GroupInfo {
+    pub(crate) consumers: i64,
+    pub(crate) pending: i64,
+    pub(crate) last_delivered_id: String,
+}
+
+/// A consumer (worker) of the submission consumer group
+#[derive(Debug)]
+pub(crate) struct ConsumerInfo {
+    pub(crate) name: String,
+    pub(crate) pending: i64,
+    pub(crate) idle_ms: i64,
+}
+
+/// Parses a stream entry ID (`<milliseconds>-<sequence>`) so that IDs can be compared
+pub(crate) fn parse_stream_id(id: &str) -> Option<(u64, u64)> {
+    let mut parts = id.splitn(2, '-');
+    let ms = parts.next()?.parse().ok()?;
+    let seq = match parts.next() {
+        Some(seq) => seq.parse().ok()?,
+        None => 0,
+    };
+    Some((ms, seq))
+}
+
+/// Fetches the submission consumer group via XINFO GROUPS
+///
+/// Returns `None` if the stream or the group does not exist (yet).
+pub(crate) async fn submission_group_info(redis: &Addr<RedisActor>) -> AWResult<Option<GroupInfo>> {
+    let resp = redis
+        .send(Command(resp_array![
+            "XINFO",
+            "GROUPS",
+            FABSEAL_SUBMISSION_QUEUE
+        ]))
+        .await
+        .map_err(redis_error("XINFO"))?
+        .map_err(redis_error("XINFO"))?;
+
+    let groups = match resp {
+        RespValue::Array(groups) => groups,
+        RespValue::Error(e) => {
+            // "ERR no such key" if no worker has created the stream yet
+            debug!("XINFO GROUPS failed: {}", e);
+            return Ok(None);
+        }
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+        }
+    };
+
+    for group in groups {
+        let mut map = convert_map_response(group)?;
+        if take_string_field(&mut map, "name")?.as_deref()
+            != Some(FABSEAL_SUBMISSION_CONSUMER_GROUP)
+        {
+            continue;
+        }
+
+        return Ok(Some(GroupInfo {
+            consumers: take_integer_field(&mut map, "consumers")?.unwrap_or(0),
+            pending: take_integer_field(&mut map, "pending")?.unwrap_or(0),
+            last_delivered_id: take_string_field(&mut map, "last-delivered-id")?
+                .unwrap_or_else(|| "0-0".to_string()),
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Fetches the consumers of the submission consumer group via XINFO CONSUMERS
+///
+/// Returns an empty list if the stream or the group does not exist (yet).
+pub(crate) async fn submission_consumers(redis: &Addr<RedisActor>) -> AWResult<Vec<ConsumerInfo>> {
+    let resp = redis
+        .send(Command(resp_array![
+            "XINFO",
+            "CONSUMERS",
+            FABSEAL_SUBMISSION_QUEUE,
+            FABSEAL_SUBMISSION_CONSUMER_GROUP
+        ]))
+        .await
+        .map_err(redis_error("XINFO"))?
+        .map_err(redis_error("XINFO"))?;
+
+    let consumers = match resp {
+        RespValue::Array(consumers) => consumers,
+        RespValue::Error(e) => {
+            // "ERR no such key" or "NOGROUP" if no worker has created the stream yet
+            debug!("XINFO CONSUMERS failed: {}", e);
+            return Ok(vec![]);
+        }
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+        }
+    };
+
+    consumers
+        .into_iter()
+        .map(|consumer| {
+            let mut map = convert_map_response(consumer)?;
+            Ok(ConsumerInfo {
+                name: take_string_field(&mut map, "name")?.unwrap_or_default(),
+                pending: take_integer_field(&mut map, "pending")?.unwrap_or(0),
+                idle_ms: take_integer_field(&mut map, "idle")?.unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
+/// Number of entries in the submission stream that have not been delivered to a worker yet
+///
+/// Same as the lag that Redis 7 reports in XINFO GROUPS, counted here for older versions.
+/// Without a consumer group (no worker has started yet), every entry is undelivered.
+pub(crate) async fn undelivered_count(
+    redis: &Addr<RedisActor>,
+    info: Option<&GroupInfo>,
+) -> AWResult<i64> {
+    let last_delivered_id = match info {
+        Some(info) => info.last_delivered_id.as_str(),
+        None => {
+            let resp = redis
+                .send(Command(resp_array!["XLEN", FABSEAL_SUBMISSION_QUEUE]))
+                .await
+                .map_err(redis_error("XLEN"))?
+                .map_err(redis_error("XLEN"))?;
+            return match resp {
+                RespValue::Integer(len) => Ok(len),
+                _ => {
+                    error!("Unexpected Redis response: {:?}", resp);
+                    Err(actix_web::error::ErrorInternalServerError("Redis error"))
+                }
+            };
+        }
+    };
+
+    let resp = redis
+        .send(Command(resp_array![
+            "XRANGE",
+            FABSEAL_SUBMISSION_QUEUE,
+            last_delivered_id,
+            "+"
+        ]))
+        .await
+        .map_err(redis_error("XRANGE"))?
+        .map_err(redis_error("XRANGE"))?;
+
+    let entries = match resp {
+        RespValue::Array(entries) => entries,
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+        }
+    };
+
+    // XRANGE is inclusive: skip the last delivered entry
+    let last_delivered = parse_stream_id(last_delivered_id);
+    let mut undelivered = 0;
+    for entry in entries {
+        let id = match entry {
+            RespValue::Array(fields) => fields.into_iter().next(),
+            _ => None,
+        };
+        if let Some(id) = id {
+            if parse_stream_id(&convert_string_response(id)?) > last_delivered {
+                undelivered += 1;
+            }
+        }
+    }
+
+    Ok(undelivered)
+}
+
+/// Number of not yet delivered entries in front of `entry_id` in the submission stream
+///
+/// Returns `None` if the entry has already been delivered to a worker.
+pub(crate) async fn queue_position(
+    redis: &Addr<RedisActor>,
+    entry_id: &str,
+) -> AWResult<Option<u64>> {
+    let last_delivered_id = match submission_group_info(redis).await? {
+        Some(info) => info.last_delivered_id,
+        None => "0-0".to_string(),
+    };
+
+    let last_delivered = parse_stream_id(&last_delivered_id);
+    if parse_stream_id(entry_id) <= last_delivered {
+        return Ok(None);
+    }
+
+    let resp = redis
+        .send(Command(resp_array![
+            "XRANGE",
+            FABSEAL_SUBMISSION_QUEUE,
+            last_delivered_id,
+            entry_id
+        ]))
+        .await
+        .map_err(redis_error("XRANGE"))?
+        .map_err(redis_error("XRANGE"))?;
+
+    let entries = match resp {
+        RespValue::Array(entries) => entries,
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+        }
+    };
+
+    // XRANGE is inclusive on both ends: skip the last delivered entry and our own
+    let own = parse_stream_id(entry_id);
+    let mut ahead: u64 = 0;
+    for entry in entries {
+        let id = match entry {
+            RespValue::Array(fields) => fields.into_iter().next(),
+            _ => None,
+        };
+        if let Some(id) = id {
+            let id = parse_stream_id(&convert_string_response(id)?);
+            if id > last_delivered && id < own {
+                ahead += 1;
+            }
+        }
+    }
+
+    Ok(Some(ahead))
+}
+
+/// Adds a job to the submission stream unless the queue is full, and marks it as queued
+///
+/// Jobs waiting for a worker or being processed count towards the limit. They are counted within
+/// the script, so that concurrent submissions cannot exceed the limit.
+///
+/// KEYS: submission stream, job status.
+/// ARGV: queue limit, consumer group, request id, request settings (JSON), queued state,
+/// current time, TTL of the status in seconds.
+/// Returns the entry ID, or nil if the queue is full.
+const SUBMIT_JOB_SCRIPT: &str = r#"
+-- XINFO is non-deterministic, only replicate the effects (the default since Redis 7)
+redis.replicate_commands()
+local limit = tonumber(ARGV[1])
+local outstanding = nil
+local ok, groups = pcall(redis.call, 'XINFO', 'GROUPS', KEYS[1])
+if ok then
+  for _, group in ipairs(groups) do
+    local info = {}
+    for i = 1, #group, 2 do
+      info[group[i]] = group[i + 1]
+    end
+    if info['name'] == ARGV[2] then
+      -- Only reported by Redis 7 and newer
+      local undelivered = info['lag']
+      if type(undelivered) ~= 'number' then
+        undelivered = 0
+        local last_delivered_id = info['last-delivered-id']
+        local entries = redis.call('XRANGE', KEYS[1], last_delivered_id, '+', 'COUNT', limit + 1)
+        for _, entry in ipairs(entries) do
+          if entry[1] ~= last_delivered_id then
+            undelivered = undelivered + 1
+          end
+        end
+      end
+      outstanding = info['pending'] + undelivered
+    end
+  end
+end
+-- No worker has started yet, every entry is waiting
+if outstanding == nil then
+  outstanding = redis.call('XLEN', KEYS[1])
+end
+if outstanding >= limit then
+  return false
+end
+
+-- Reset the job status before queueing, so that a fast worker cannot have its update overwritten
+redis.call('DEL', KEYS[2])
+redis.call('HSET', KEYS[2], 'state', ARGV[5], 'queued_at', ARGV[6], 'settings', ARGV[4])
+local entry_id = redis.call('XADD', KEYS[1], '*', 'request_id', ARGV[3], 'settings', ARGV[4])
+redis.call('HSET', KEYS[2], 'entry_id', entry_id)
+redis.call('EXPIRE', KEYS[2], ARGV[7])
+return entry_id
+"#;
+
+/// Submits a job, returns `None` if the queue is full
+///
+/// At most `limit` jobs are waiting for a worker or being processed at any time.
+pub(crate) async fn submit_job(
+    redis: &Addr<RedisActor>,
+    id: RequestId,
+    settings_json: &str,
+    limit: u32,
+    ttl: u32,
+) -> AWResult<Option<String>> {
+    let resp = redis
+        .send(Command(resp_array![
+            "EVAL",
+            SUBMIT_JOB_SCRIPT,
+            "2",
+            FABSEAL_SUBMISSION_QUEUE,
+            status_key(id),
+            limit.to_string(),
+            FABSEAL_SUBMISSION_CONSUMER_GROUP,
+            &id.as_bytes()[..],
+            settings_json,
+            JobState::Queued.as_str(),
+            unix_timestamp().to_string(),
+            ttl.to_string()
+        ]))
+        .await
+        .map_err(redis_error("EVAL"))?
+        .map_err(redis_error("EVAL"))?;
+
+    match resp {
+        RespValue::Nil => Ok(None),
+        resp => convert_string_response(resp).map(Some),
+    }
+}
+
+/// Removes entries that have been delivered and acknowledged from the submission stream
+///
+/// Entries that are waiting for a worker or pending (being processed, or to be reclaimed) are kept.
+/// Returns the number of removed entries.
+pub(crate) async fn trim_acknowledged(redis: &Addr<RedisActor>) -> AWResult<i64> {
+    let info = match submission_group_info(redis).await? {
+        Some(info) => info,
+        None => return Ok(0),
+    };
+
+    // Everything before the oldest pending entry has been acknowledged,
+    // or everything up to the last delivered entry if nothing is pending
+    let min_id = if info.pending > 0 {
+        let resp = redis
+            .send(Command(resp_array![
+                "XPENDING",
+                FABSEAL_SUBMISSION_QUEUE,
+                FABSEAL_SUBMISSION_CONSUMER_GROUP
+            ]))
+            .await
+            .map_err(redis_error("XPENDING"))?
+            .map_err(redis_error("XPENDING"))?;
+
+        // [count, smallest id, greatest id, [[consumer, count], ...]]
+        let smallest = match resp {
+            RespValue::Array(summary) => summary.into_iter().nth(1),
+            _ => None,
+        };
+        match smallest {
+            Some(RespValue::Nil) | None => return Ok(0),
+            Some(id) => convert_string_response(id)?,
+        }
+    } else {
+        match parse_stream_id(&info.last_delivered_id) {
+            Some((0, 0)) | None => return Ok(0),
+            // MINID removes entries with smaller IDs only
+            Some((ms, seq)) => format!("{}-{}", ms, seq + 1),
+        }
+    };
+
+    let resp = redis
+        .send(Command(resp_array![
+            "XTRIM",
+            FABSEAL_SUBMISSION_QUEUE,
+            "MINID",
+            min_id.as_str()
+        ]))
+        .await
+        .map_err(redis_error("XTRIM"))?
+        .map_err(redis_error("XTRIM"))?;
+
+    match resp {
+        RespValue::Integer(removed) => {
+            debug!("trimmed {} acknowledged entries before {}", removed, min_id);
+            Ok(removed)
+        }
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            Err(actix_web::error::ErrorInternalServerError("Redis error"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_stream_id;
+
+    #[test]
+    fn parses_stream_ids() {
+        assert_eq!(
+            parse_stream_id("1526919030474-55"),
+            Some((1526919030474, 55))
+        );
+        assert_eq!(parse_stream_id("0-0"), Some((0, 0)));
+        // The sequence number is optional in range queries
+        assert_eq!(parse_stream_id("1526919030474"), Some((1526919030474, 0)));
+    }
+
+    #[test]
+    fn orders_stream_ids() {
+        assert!(parse_stream_id("1-1") < parse_stream_id("1-2"));
+        assert!(parse_stream_id("1-10") > parse_stream_id("1-9"));
+        assert!(parse_stream_id("2-0") > parse_stream_id("1-99"));
+    }
+
+    #[test]
+    fn rejects_malformed_stream_ids() {
+        assert_eq!(parse_stream_id(""), None);
+        assert_eq!(parse_stream_id("-"), None);
+        assert_eq!(parse_stream_id("1-"), None);
+        assert_eq!(parse_stream_id("-1"), None);
+        assert_eq!(parse_stream_id("a-1"), None);
+        assert_eq!(parse_stream_id("1-b"), None);
+        assert_eq!(parse_stream_id("1-2-3"), None);
+        assert_eq!(parse_stream_id("+"), None);
+    }
+}
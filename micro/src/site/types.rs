@@ -1,4 +1,8 @@
-use serde::Deserialize;
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use fabseal_micro_common::JobState;
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all(deserialize = "lowercase"))]
@@ -7,8 +11,248 @@ pub(crate) enum ResultType {
     Heightmap,
 }
 
+impl ResultType {
+    /// Field name of this result type in a public upload hash
+    pub(crate) fn field_name(&self) -> &'static str {
+        match self {
+            ResultType::Model => "model",
+            ResultType::Heightmap => "heightmap",
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub(crate) struct ResultRequestInfo {
     #[serde(rename = "type")]
     pub(crate) result_type: ResultType,
 }
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct PublicResultRequestInfo {
+    pub(crate) id: String,
+    #[serde(rename = "type")]
+    pub(crate) result_type: ResultType,
+}
+
+/// Progress of a create request, stored in the `state` field of `request_key`
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RequestState {
+    New,
+    Uploaded,
+    Started,
+    Finished,
+}
+
+impl RequestState {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            RequestState::New => "new",
+            RequestState::Uploaded => "uploaded",
+            RequestState::Started => "started",
+            RequestState::Finished => "finished",
+        }
+    }
+}
+
+impl fmt::Display for RequestState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for RequestState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "new" => Ok(RequestState::New),
+            "uploaded" => Ok(RequestState::Uploaded),
+            "started" => Ok(RequestState::Started),
+            "finished" => Ok(RequestState::Finished),
+            _ => Err(format!("unknown request state \u{201C}{}\u{201D}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct NewRequestResponse {
+    pub(crate) request_id: String,
+    /// Job token, identifies the request instead of the session cookie
+    pub(crate) token: String,
+}
+
+/// Dataset image that feedback refers to
+#[derive(Deserialize, Debug)]
+pub(crate) struct FeedbackPath {
+    pub(crate) dataset_name: String,
+    pub(crate) image_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct FeedbackRequestInfo {
+    pub(crate) id: String,
+}
+
+/// An image attached to feedback
+#[derive(Deserialize, Debug)]
+pub(crate) struct FeedbackImagePath {
+    pub(crate) dataset_name: String,
+    pub(crate) image_id: String,
+    pub(crate) feedback_id: String,
+    pub(crate) feedback_image_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct FeedbackListInfo {
+    #[serde(default)]
+    pub(crate) offset: u64,
+    pub(crate) limit: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct FeedbackImageResponse {
+    pub(crate) id: String,
+    pub(crate) url: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct FeedbackResponse {
+    pub(crate) id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<i64>,
+    pub(crate) metadata: serde_json::Value,
+    pub(crate) images: Vec<FeedbackImageResponse>,
+}
+
+/// A page of the feedback on a dataset image, newest first
+#[derive(Serialize, Debug)]
+pub(crate) struct FeedbackListResponse {
+    /// Number of feedback entries on the dataset image
+    pub(crate) total: u64,
+    pub(crate) offset: u64,
+    pub(crate) limit: u64,
+    pub(crate) feedback: Vec<FeedbackResponse>,
+}
+
+/// A worker, combining its heartbeat and its consumer in the submission consumer group
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct WorkerInfoResponse {
+    pub(crate) consumer: String,
+    /// Whether the worker published a heartbeat recently
+    pub(crate) alive: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pid: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) current_request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) jobs_done: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) jobs_failed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) started_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) last_seen: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) backend: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) backend_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) version: Option<String>,
+    /// Messages delivered to the consumer, but not acknowledged yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pending: Option<i64>,
+    /// Milliseconds since the consumer last read from the stream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) idle_ms: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct WorkersResponse {
+    pub(crate) workers: Vec<WorkerInfoResponse>,
+}
+
+/// A session stored in Redis
+#[derive(Serialize, Debug)]
+pub(crate) struct SessionInfoResponse {
+    pub(crate) id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) updated_at: Option<i64>,
+    pub(crate) expires_at: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct SessionListInfo {
+    #[serde(default)]
+    pub(crate) offset: u64,
+    pub(crate) limit: Option<u64>,
+}
+
+/// A page of the sessions stored in Redis, ordered by expiry
+#[derive(Serialize, Debug)]
+pub(crate) struct SessionsResponse {
+    /// Number of registered sessions
+    pub(crate) total: u64,
+    pub(crate) offset: u64,
+    pub(crate) limit: u64,
+    pub(crate) sessions: Vec<SessionInfoResponse>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct HealthResponse {
+    pub(crate) status: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct CheckResult {
+    pub(crate) ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) detail: Option<String>,
+}
+
+impl CheckResult {
+    pub(crate) fn ok(detail: Option<String>) -> Self {
+        CheckResult { ok: true, detail }
+    }
+
+    pub(crate) fn failed(detail: &str) -> Self {
+        CheckResult {
+            ok: false,
+            detail: Some(detail.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ReadinessChecks {
+    pub(crate) redis: CheckResult,
+    pub(crate) queue: CheckResult,
+    pub(crate) workers: CheckResult,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ReadinessResponse {
+    pub(crate) ready: bool,
+    pub(crate) checks: ReadinessChecks,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct JobStatusResponse {
+    pub(crate) state: JobState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) queue_position: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) queued_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) started_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) finished_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
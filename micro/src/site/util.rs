@@ -1,14 +1,20 @@
+use std::collections::HashMap;
+
+use actix::Addr;
 use actix_multipart as mp;
+use actix_redis::Command;
 use actix_session::Session;
 use actix_web::Result as AWResult;
 
 use futures_util::stream::StreamExt;
 
-use redis_async::resp::RespValue;
+use redis_async::{resp::RespValue, resp_array};
 
 use log::{debug, error, info, warn};
 
-use fabseal_micro_common::{ImageType, RequestId};
+use fabseal_micro_common::{request_key, ImageType, RequestId};
+
+use crate::{metrics, redis_connection::RedisActor, site::types::RequestState};
 
 pub(crate) const REQUEST_ID_COOKIE_KEY: &str = "request-id";
 
@@ -24,13 +30,78 @@ pub(crate) fn new_request_cookie(session: &Session) -> AWResult<RequestId> {
 
 pub(crate) fn request_cookie(session: &Session) -> AWResult<RequestId> {
     match session.get::<RequestId>(REQUEST_ID_COOKIE_KEY)? {
-        None => {
-            new_request_cookie(session)
-        }
+        None => Err(actix_web::error::ErrorBadRequest(
+            "No active request, use /create/new first",
+        )),
         Some(rid) => Ok(rid),
     }
 }
 
+pub(crate) async fn expire(redis: &Addr<RedisActor>, key: String, ttl: u32) -> AWResult<()> {
+    let resp = redis
+        .send(Command(resp_array!["EXPIRE", key, ttl.to_string()]))
+        .await
+        .map_err(redis_error("EXPIRE"))?
+        .map_err(redis_error("EXPIRE"))?;
+
+    match resp {
+        RespValue::Integer(_) => Ok(()),
+        _ => {
+            error!("Unexpected Redis response: {:?}", resp);
+            Err(actix_web::error::ErrorInternalServerError("Redis error"))
+        }
+    }
+}
+
+pub(crate) async fn request_state(
+    redis: &Addr<RedisActor>,
+    id: RequestId,
+) -> AWResult<RequestState> {
+    let resp = redis
+        .send(Command(resp_array!["HGET", request_key(id), "state"]))
+        .await
+        .map_err(redis_error("HGET"))?
+        .map_err(redis_error("HGET"))?;
+
+    let state = match resp {
+        RespValue::Nil => {
+            info!("unknown (or expired) request {}", id);
+            return Err(actix_web::error::ErrorNotFound("Unknown request"));
+        }
+        resp => convert_string_response(resp)?,
+    };
+
+    state.parse().map_err(|e| {
+        error!("invalid state for request {}: {}", id, e);
+        actix_web::error::ErrorInternalServerError("Invalid request state")
+    })
+}
+
+pub(crate) async fn set_request_state(
+    redis: &Addr<RedisActor>,
+    id: RequestId,
+    state: RequestState,
+    ttl: u32,
+) -> AWResult<()> {
+    let resp = redis
+        .send(Command(resp_array![
+            "HSET",
+            request_key(id),
+            "state",
+            state.as_str()
+        ]))
+        .await
+        .map_err(redis_error("HSET"))?
+        .map_err(redis_error("HSET"))?;
+
+    if let RespValue::Error(e) = resp {
+        error!("Redis error: {}", e);
+        return Err(actix_web::error::ErrorInternalServerError("Redis error"));
+    }
+
+    expire(redis, request_key(id), ttl).await
+}
+
 const UPLOAD_LIMIT: usize = 8 * 1024 * 1024;
 
 pub(crate) async fn read_byte_chunks(field: &mut mp::Field) -> AWResult<Vec<u8>> {
@@ -85,12 +156,72 @@ pub(crate) fn convert_bytes_response(resp: RespValue) -> AWResult<Vec<u8>> {
     }
 }
 
+pub(crate) fn convert_string_response(resp: RespValue) -> AWResult<String> {
+    let data = match resp {
+        RespValue::SimpleString(s) => return Ok(s),
+        resp => convert_bytes_response(resp)?,
+    };
+    String::from_utf8(data).map_err(|e| {
+        error!("Redis error: invalid UTF-8 in response: {}", e);
+        actix_web::error::ErrorInternalServerError("Redis error")
+    })
+}
+
+/// Converts a flat array of alternating keys and values (as returned by HGETALL or XINFO)
+pub(crate) fn convert_map_response(resp: RespValue) -> AWResult<HashMap<String, RespValue>> {
+    match resp {
+        RespValue::Array(values) => {
+            let mut map = HashMap::with_capacity(values.len() / 2);
+            let mut it = values.into_iter();
+            while let (Some(k), Some(v)) = (it.next(), it.next()) {
+                map.insert(convert_string_response(k)?, v);
+            }
+            Ok(map)
+        }
+        RespValue::Error(e) => {
+            error!("Redis error: {}", e);
+            Err(actix_web::error::ErrorInternalServerError("Redis error"))
+        }
+        _ => {
+            error!("Redis error: Unexpected response");
+            Err(actix_web::error::ErrorInternalServerError("Redis error"))
+        }
+    }
+}
+
+/// Removes a string field from a map returned by `convert_map_response`
+pub(crate) fn take_string_field(
+    map: &mut HashMap<String, RespValue>,
+    field: &str,
+) -> AWResult<Option<String>> {
+    map.remove(field).map(convert_string_response).transpose()
+}
+
+/// Removes an integer field (which might be stored as a string) from a map returned by `convert_map_response`
+pub(crate) fn take_integer_field(
+    map: &mut HashMap<String, RespValue>,
+    field: &str,
+) -> AWResult<Option<i64>> {
+    match map.remove(field) {
+        None => Ok(None),
+        Some(RespValue::Integer(i)) => Ok(Some(i)),
+        Some(resp) => convert_string_response(resp)?
+            .parse()
+            .map(Some)
+            .map_err(|e| {
+                error!("Redis error: invalid integer in field {}: {}", field, e);
+                actix_web::error::ErrorInternalServerError("Redis error")
+            }),
+    }
+}
+
 pub(crate) fn redis_error<T>(cmd: &str) -> impl FnOnce(T) -> actix_web::error::Error + '_
 where
     T: std::error::Error,
 {
     move |e| {
         error!("Error while running {}: {}", cmd, e);
+        metrics::REDIS_ERRORS.with_label_values(&[cmd]).inc();
         actix_web::error::ErrorInternalServerError("Redis error")
     }
 }
@@ -1,11 +1,41 @@
-use log::debug;
+use std::{fs, io::Write, path::Path, thread, time::Duration};
 
-use color_eyre::eyre::Result;
+use log::{debug, error, info};
 
+use color_eyre::eyre::{eyre, Result};
+use tempfile::{NamedTempFile, TempPath};
+
+mod metrics;
 mod settings;
 use settings::Settings;
 mod worker;
-use crate::worker::Worker;
+use crate::worker::{check, requeue_dead, Shutdown, Worker};
+
+/// Makes OpenSSL trust the CA certificate(s) in `ca_file` in addition to the system roots
+///
+/// The redis crate builds its own TLS connector, which does not allow adding root certificates.
+/// OpenSSL reads its CA bundle from `SSL_CERT_FILE` instead (before any connection is made),
+/// so this points it to a copy of the system bundle with `ca_file` appended.
+/// The copy is removed when the returned path is dropped. Blender is started without
+/// `SSL_CERT_FILE`, so that it does not inherit the bundle.
+fn trust_ca_file(ca_file: &Path) -> Result<TempPath> {
+    // Without the system bundle, only `ca_file` would be trusted
+    let system_bundle = openssl_probe::probe().cert_file.ok_or_else(|| {
+        eyre!(
+            "no system CA bundle found to add redis.tls_ca_file to \
+             (set SSL_CERT_FILE to the system bundle)"
+        )
+    })?;
+
+    let mut bundle = NamedTempFile::new()?;
+    bundle.write_all(&fs::read(system_bundle)?)?;
+    bundle.write_all(b"\n")?;
+    bundle.write_all(&fs::read(ca_file)?)?;
+
+    let path = bundle.into_temp_path();
+    std::env::set_var("SSL_CERT_FILE", &path);
+    Ok(path)
+}
 
 fn main() -> Result<()> {
     color_eyre::install()?;
@@ -15,8 +45,47 @@ fn main() -> Result<()> {
     let settings = Settings::new()?;
     debug!("settings: {:?}", settings);
 
-    let mut worker = Worker::create(settings)?;
-    worker.run();
+    let _ca_bundle = match &settings.redis.tls_ca_file {
+        Some(ca_file) => Some(trust_ca_file(ca_file)?),
+        None => None,
+    };
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("--requeue-dead") => {
+            let requeued = requeue_dead(&settings, &args[1..])?;
+            info!("requeued {} jobs from the dead-letter stream", requeued);
+            return Ok(());
+        }
+        Some("--check") => return check(&settings),
+        _ => {}
+    }
+
+    if let Some(endpoint) = &settings.metrics.endpoint {
+        metrics::serve(endpoint)?;
+    }
+
+    let shutdown = Shutdown::register(Duration::from_secs(settings.shutdown.drain_timeout))?;
+
+    let workers = (0..settings.concurrency.max(1))
+        .map(|_| Worker::create(settings.clone(), &shutdown))
+        .collect::<Result<Vec<_>>>()?;
+
+    let handles = workers
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut worker)| {
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || worker.run())
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    for handle in handles {
+        if handle.join().is_err() {
+            error!("worker thread panicked");
+        }
+    }
 
     Ok(())
 }
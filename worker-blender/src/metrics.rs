@@ -0,0 +1,109 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use prometheus::{
+    exponential_buckets, register_counter, register_histogram, register_histogram_vec,
+    register_int_counter_vec, Counter, Encoder, Histogram, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+
+use color_eyre::eyre::Result;
+
+lazy_static! {
+    /// Outcome is one of `done`, `failed` or `expired` (the image was gone)
+    pub(crate) static ref JOBS: IntCounterVec = register_int_counter_vec!(
+        "fabseal_worker_jobs_total",
+        "Number of processed jobs by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub(crate) static ref MESH_DURATION: HistogramVec = register_histogram_vec!(
+        "fabseal_worker_mesh_duration_seconds",
+        "Wall time of the mesh backend (e.g. Blender) per attempt",
+        &["backend"],
+        exponential_buckets(0.5, 2.0, 11).unwrap()
+    )
+    .unwrap();
+    pub(crate) static ref RESULT_SIZE: Histogram = register_histogram!(
+        "fabseal_worker_result_size_bytes",
+        "Size of generated models",
+        exponential_buckets(64.0 * 1024.0, 2.0, 10).unwrap()
+    )
+    .unwrap();
+    /// Operation is e.g. `read_stream`, `reclaim` or `set_status`
+    pub(crate) static ref REDIS_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "fabseal_worker_redis_errors_total",
+        "Number of Redis errors by operation",
+        &["operation"]
+    )
+    .unwrap();
+    pub(crate) static ref READ_STREAM_BLOCKED: Counter = register_counter!(
+        "fabseal_worker_read_stream_blocked_seconds_total",
+        "Time spent waiting for new jobs in XREADGROUP"
+    )
+    .unwrap();
+}
+
+/// Scrapes are served one at a time, so a stalled client must not block the listener
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LINE_LENGTH: u64 = 8 * 1024;
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut request_line = String::new();
+    BufReader::new((&stream).take(MAX_REQUEST_LINE_LENGTH)).read_line(&mut request_line)?;
+    debug!("metrics request: {}", request_line.trim_end());
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    if !request_line.starts_with("GET ") || path != "/metrics" {
+        return stream.write_all(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut body) {
+        warn!("error while encoding metrics: {}", e);
+        return stream.write_all(
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+    }
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        encoder.format_type(),
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+/// Serves the metrics at `http://<endpoint>/metrics` from a background thread
+///
+/// This is a minimal HTTP/1.1 responder that only handles Prometheus scrapes.
+pub(crate) fn serve(endpoint: &str) -> Result<()> {
+    let listener = TcpListener::bind(endpoint)?;
+    info!("serving metrics on http://{}/metrics", endpoint);
+
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream.and_then(respond);
+                if let Err(e) = res {
+                    warn!("error while serving metrics: {}", e);
+                }
+            }
+        })?;
+
+    Ok(())
+}
@@ -1,13 +1,187 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use config::{Config, ConfigError, Environment, File};
 
 use fabseal_micro_common::settings::{Limits, RedisSettings};
 use serde::Deserialize;
 
+/// Mesh backend used to turn heightmaps into models
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// displacementMapToStl running in Blender (requires `dmstl_directory`)
+    #[default]
+    Blender,
+    /// Built-in mesher (see `NativeMesherSettings`)
+    Native,
+    /// Deterministic placeholder model, for testing
+    Fake,
+}
+
+/// Physical dimensions of models generated by the native backend
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct NativeMesherSettings {
+    /// Length of the longer side of the model in millimetres
+    pub size_mm: f32,
+    /// Height difference between black and white pixels in millimetres
+    pub relief_mm: f32,
+    /// Thickness of the base below the relief in millimetres
+    pub base_mm: f32,
+    /// Maximum number of samples along the longer side of the heightmap
+    pub max_resolution: u32,
+}
+
+impl Default for NativeMesherSettings {
+    fn default() -> Self {
+        Self {
+            size_mm: 40.0,
+            relief_mm: 2.0,
+            base_mm: 2.0,
+            max_resolution: 400,
+        }
+    }
+}
+
+/// Recovery of jobs left pending by crashed workers
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ReclaimSettings {
+    /// Pending jobs idle for longer than this (in milliseconds) are claimed by other workers.
+    /// Must be longer than one attempt plus the delay before it (the claim is renewed before
+    /// each retry), and shorter than `limits.image_ttl`, so that reclaimed jobs still find their image.
+    pub min_idle_ms: u64,
+    /// Interval between checks for stalled jobs (in seconds)
+    pub interval: u64,
+    /// Jobs delivered this many times are marked as failed instead of being retried again
+    pub max_deliveries: usize,
+}
+
+impl Default for ReclaimSettings {
+    fn default() -> Self {
+        Self {
+            min_idle_ms: 5 * 60 * 1000,
+            interval: 30,
+            max_deliveries: 3,
+        }
+    }
+}
+
+/// Limits for a single job
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct JobSettings {
+    /// Maximum duration of a single attempt (in seconds), the mesh backend is killed afterwards
+    pub timeout: u64,
+}
+
+impl Default for JobSettings {
+    fn default() -> Self {
+        Self { timeout: 4 * 60 }
+    }
+}
+
+/// Graceful shutdown on SIGTERM/SIGINT
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ShutdownSettings {
+    /// Time running jobs get to finish (in seconds) before they are aborted and left pending.
+    /// Should be shorter than the stop timeout of the service manager (10 seconds for Docker).
+    pub drain_timeout: u64,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self { drain_timeout: 8 }
+    }
+}
+
+/// Handling of failed jobs
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetrySettings {
+    /// Number of attempts before a job is moved to the dead-letter stream
+    pub max_attempts: u32,
+    /// Delay before the first retry (in milliseconds), doubled for each further retry
+    pub initial_backoff_ms: u64,
+    /// Upper bound for the delay between retries (in milliseconds)
+    pub max_backoff_ms: u64,
+    /// Approximate maximum length of the dead-letter stream
+    pub dead_letter_limit: usize,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30 * 1000,
+            dead_letter_limit: 1000,
+        }
+    }
+}
+
+impl RetrySettings {
+    /// Longest delay before a retry of a job (zero without retries)
+    pub fn longest_backoff(&self) -> Duration {
+        match self.max_attempts {
+            0 | 1 => Duration::ZERO,
+            max_attempts => self.backoff(max_attempts - 1),
+        }
+    }
+
+    /// Delay before the given retry (starting at 1)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u64 << retry.saturating_sub(1).min(16);
+        Duration::from_millis(
+            self.initial_backoff_ms
+                .saturating_mul(factor)
+                .min(self.max_backoff_ms),
+        )
+    }
+}
+
+/// Prometheus metrics exporter
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// Address to serve `/metrics` on (e.g. "127.0.0.1:9187"), disabled if unset
+    pub endpoint: Option<String>,
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
-    pub dmstl_directory: PathBuf,
+    #[serde(default)]
+    pub backend: BackendKind,
+
+    /// Number of jobs processed in parallel, each by its own consumer
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+
+    #[serde(default)]
+    pub dmstl_directory: Option<PathBuf>,
+
+    #[serde(default)]
+    pub native: NativeMesherSettings,
+
+    #[serde(default)]
+    pub reclaim: ReclaimSettings,
+
+    #[serde(default)]
+    pub retry: RetrySettings,
+
+    #[serde(default)]
+    pub job: JobSettings,
+
+    #[serde(default)]
+    pub shutdown: ShutdownSettings,
+
+    #[serde(default)]
+    pub metrics: MetricsSettings,
 
     #[serde(default)]
     pub redis: RedisSettings,
@@ -28,6 +202,132 @@ impl Settings {
         s.merge(Environment::with_prefix("fabseal"))?;
 
         // You can deserialize (and thus freeze) the entire configuration as
-        s.try_into()
+        let settings: Settings = s.try_into()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let min_idle = Duration::from_millis(self.reclaim.min_idle_ms);
+
+        // Jobs must not be reclaimed while they are still being processed,
+        // the claim is renewed before each retry
+        let longest_attempt = Duration::from_secs(self.job.timeout) + self.retry.longest_backoff();
+        if min_idle <= longest_attempt {
+            return Err(ConfigError::Message(format!(
+                "reclaim.min_idle_ms ({}) has to be longer than an attempt of a job \
+                 (job.timeout + longest retry backoff = {} ms)",
+                self.reclaim.min_idle_ms,
+                longest_attempt.as_millis()
+            )));
+        }
+
+        // Reclaimed jobs have to find their image, which expires after limits.image_ttl
+        let latest_reclaim = min_idle + Duration::from_secs(self.reclaim.interval);
+        if latest_reclaim >= Duration::from_secs(self.limits.image_ttl.into()) {
+            return Err(ConfigError::Message(format!(
+                "reclaim.min_idle_ms + reclaim.interval ({} ms) has to be shorter than \
+                 limits.image_ttl ({} s)",
+                latest_reclaim.as_millis(),
+                self.limits.image_ttl
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry(initial_backoff_ms: u64, max_backoff_ms: u64) -> RetrySettings {
+        RetrySettings {
+            initial_backoff_ms,
+            max_backoff_ms,
+            ..RetrySettings::default()
+        }
+    }
+
+    fn settings(min_idle_ms: u64) -> Settings {
+        Settings {
+            backend: BackendKind::default(),
+            concurrency: default_concurrency(),
+            dmstl_directory: None,
+            native: NativeMesherSettings::default(),
+            reclaim: ReclaimSettings {
+                min_idle_ms,
+                ..ReclaimSettings::default()
+            },
+            retry: RetrySettings::default(),
+            job: JobSettings::default(),
+            shutdown: ShutdownSettings::default(),
+            metrics: MetricsSettings::default(),
+            redis: RedisSettings::default(),
+            limits: Limits::default(),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        settings(ReclaimSettings::default().min_idle_ms)
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn min_idle_has_to_cover_an_attempt() {
+        // job.timeout is 240 s and the longest backoff 2 s by default
+        assert!(settings(242 * 1000).validate().is_err());
+        assert!(settings(243 * 1000).validate().is_ok());
+    }
+
+    #[test]
+    fn reclaimed_jobs_have_to_find_their_image() {
+        // limits.image_ttl is 600 s and reclaim.interval 30 s by default
+        assert!(settings(570 * 1000 - 1).validate().is_ok());
+        assert!(settings(570 * 1000).validate().is_err());
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let retry = retry(1000, 60 * 1000);
+        assert_eq!(retry.backoff(1), Duration::from_millis(1000));
+        assert_eq!(retry.backoff(2), Duration::from_millis(2000));
+        assert_eq!(retry.backoff(3), Duration::from_millis(4000));
+        assert_eq!(retry.backoff(4), Duration::from_millis(8000));
+    }
+
+    #[test]
+    fn backoff_is_clamped() {
+        let retry = retry(1000, 5000);
+        assert_eq!(retry.backoff(3), Duration::from_millis(4000));
+        assert_eq!(retry.backoff(4), Duration::from_millis(5000));
+        assert_eq!(retry.backoff(100), Duration::from_millis(5000));
+        assert_eq!(retry.backoff(u32::MAX), Duration::from_millis(5000));
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        let retry = retry(u64::MAX / 2, u64::MAX);
+        assert_eq!(retry.backoff(64), Duration::from_millis(u64::MAX));
+    }
+
+    #[test]
+    fn longest_backoff_is_before_the_last_retry() {
+        let retry = RetrySettings {
+            max_attempts: 3,
+            ..retry(1000, 3000)
+        };
+        assert_eq!(retry.longest_backoff(), Duration::from_millis(2000));
+        let retry = RetrySettings {
+            max_attempts: 5,
+            ..retry
+        };
+        assert_eq!(retry.longest_backoff(), Duration::from_millis(3000));
+        let retry = RetrySettings {
+            max_attempts: 1,
+            ..retry
+        };
+        assert_eq!(retry.longest_backoff(), Duration::ZERO);
     }
 }
@@ -1,16 +1,21 @@
 use std::{
+    borrow::Cow,
     convert::{TryFrom, TryInto},
-    process::{Command, Stdio},
+    time::{Duration, Instant},
 };
 
 use log::{debug, error, info, trace, warn};
 
-use fabseal_micro_common::*;
+use fabseal_micro_common::{settings::RedisAddress, *};
 
 use rand::Rng;
 use redis::{
-    streams::{StreamId, StreamInfoGroupsReply, StreamReadOptions, StreamReadReply},
-    Commands, Value,
+    streams::{
+        StreamClaimOptions, StreamClaimReply, StreamId, StreamInfoConsumersReply,
+        StreamInfoGroupsReply, StreamPendingCountReply, StreamRangeReply, StreamReadOptions,
+        StreamReadReply,
+    },
+    Commands, ConnectionAddr, ConnectionInfo, RedisConnectionInfo, Value,
 };
 
 use color_eyre::eyre::Result;
@@ -20,24 +25,204 @@ use std::sync::{
     Arc,
 };
 
+mod backend;
+use crate::worker::backend::{create_backend, JobAborted, JobControl, MeshBackend, MeshMetadata};
+mod dead_letter;
 mod file_context;
-use crate::worker::file_context::CommandFileContext;
+mod heartbeat;
+use crate::worker::heartbeat::{Heartbeat, WorkerInfo};
+mod heightmap;
 mod util;
-use crate::worker::util::WorkerContext;
 
-use crate::Settings;
+use crate::{metrics, Settings};
+
+/// Shutdown flags shared by all workers of the process
+///
+/// The first SIGTERM/SIGINT stops fetching new jobs and lets running jobs finish until the drain
+/// timeout has passed, a second one (or the drain timeout) also aborts running jobs.
+#[derive(Clone)]
+pub(crate) struct Shutdown {
+    should_exit: Arc<AtomicBool>,
+    should_abort: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub(crate) fn register(drain_timeout: Duration) -> Result<Shutdown> {
+        let should_exit = Arc::new(AtomicBool::new(false));
+        let should_abort = Arc::new(AtomicBool::new(false));
+
+        for &signal in &[signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+            let should_exit = Arc::clone(&should_exit);
+            let should_abort = Arc::clone(&should_abort);
+            // SAFETY: the handler only touches atomics, which is async-signal-safe
+            unsafe {
+                signal_hook::low_level::register(signal, move || {
+                    if should_exit.swap(true, Ordering::SeqCst) {
+                        should_abort.store(true, Ordering::SeqCst);
+                    }
+                })?;
+            }
+        }
+
+        // Signal handlers cannot start timers, so a thread waits for the first signal instead
+        {
+            let should_exit = Arc::clone(&should_exit);
+            let should_abort = Arc::clone(&should_abort);
+            std::thread::Builder::new()
+                .name("shutdown".to_string())
+                .spawn(move || {
+                    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+                    while !should_exit.load(Ordering::SeqCst) {
+                        std::thread::sleep(POLL_INTERVAL);
+                    }
+                    info!(
+                        "shutting down, running jobs are aborted in {} seconds",
+                        drain_timeout.as_secs()
+                    );
+                    let deadline = Instant::now() + drain_timeout;
+                    while !should_abort.load(Ordering::SeqCst) {
+                        if Instant::now() >= deadline {
+                            warn!("drain timeout passed, aborting running jobs");
+                            should_abort.store(true, Ordering::SeqCst);
+                            break;
+                        }
+                        std::thread::sleep(POLL_INTERVAL);
+                    }
+                })?;
+        }
+
+        Ok(Shutdown {
+            should_exit,
+            should_abort,
+        })
+    }
+}
 
 pub(crate) struct Worker {
     settings: Settings,
     conn: redis::Connection,
-    ctx: WorkerContext,
+    backend: Box<dyn MeshBackend>,
     should_exit: Arc<AtomicBool>,
+    should_abort: Arc<AtomicBool>,
     consumer_name: String,
     read_options: StreamReadOptions,
+    last_reclaim: Instant,
+    heartbeat: Heartbeat,
+}
+
+/// Maximum number of stale messages claimed per reclaim run
+const RECLAIM_BATCH: usize = 16;
+
+fn connection_info(settings: &Settings) -> Result<ConnectionInfo> {
+    let redis = settings.redis.resolve()?;
+    let addr = match redis.address {
+        RedisAddress::Tcp {
+            host,
+            port,
+            tls: false,
+        } => ConnectionAddr::Tcp(host, port),
+        RedisAddress::Tcp {
+            host,
+            port,
+            tls: true,
+        } => ConnectionAddr::TcpTls {
+            host,
+            port,
+            insecure: false,
+        },
+        RedisAddress::Unix(path) => ConnectionAddr::Unix(path),
+    };
+
+    Ok(ConnectionInfo {
+        addr,
+        redis: RedisConnectionInfo {
+            db: redis.db,
+            username: redis.username,
+            password: redis.password,
+        },
+    })
+}
+
+/// Verifies the mesh backend and the Redis connection (`--check`)
+///
+/// All checks are run and logged, an error is returned if any of them failed.
+pub(crate) fn check(settings: &Settings) -> Result<()> {
+    let mut failed = 0;
+
+    match create_backend(settings).and_then(|backend| backend.check()) {
+        Ok(()) => info!("check backend ({:?}): ok", settings.backend),
+        Err(e) => {
+            error!("check backend ({:?}): {:?}", settings.backend, e);
+            failed += 1;
+        }
+    }
+
+    let redis_check = || -> Result<()> {
+        let client = redis::Client::open(connection_info(settings)?)?;
+        let mut conn = client.get_connection()?;
+        let _: String = redis::cmd("PING").query(&mut conn)?;
+
+        // Missing groups are created by the worker on startup, so this is not an error
+        match conn.xinfo_groups::<_, StreamInfoGroupsReply>(FABSEAL_SUBMISSION_QUEUE) {
+            Ok(reply)
+                if reply
+                    .groups
+                    .iter()
+                    .any(|g| g.name == FABSEAL_SUBMISSION_CONSUMER_GROUP) => {}
+            _ => warn!("consumer group does not exist yet, it will be created on startup"),
+        }
+        Ok(())
+    };
+    match redis_check() {
+        Ok(()) => info!("check redis ({}): ok", settings.redis.address),
+        Err(e) => {
+            error!("check redis ({}): {:?}", settings.redis.address, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        color_eyre::eyre::bail!("{} checks failed", failed);
+    }
+    Ok(())
+}
+
+/// Moves jobs from the dead-letter stream back to the submission stream (all jobs if `ids` is empty)
+pub(crate) fn requeue_dead(settings: &Settings, ids: &[String]) -> Result<usize> {
+    let client = redis::Client::open(connection_info(settings)?)?;
+    let mut conn = client.get_connection()?;
+    let requeued = dead_letter::requeue(&mut conn, ids, settings.limits.session_ttl)?;
+    Ok(requeued)
 }
 
 impl Drop for Worker {
     fn drop(&mut self) {
+        // Deleting a consumer discards its pending messages, keep them for other workers to reclaim
+        let pending: redis::RedisResult<StreamPendingCountReply> =
+            self.conn.xpending_consumer_count(
+                FABSEAL_SUBMISSION_QUEUE,
+                FABSEAL_SUBMISSION_CONSUMER_GROUP,
+                "-",
+                "+",
+                1,
+                &self.consumer_name,
+            );
+        match pending {
+            Ok(reply) if reply.ids.is_empty() => {}
+            Ok(_) => {
+                warn!(
+                    "Consumer {} still has pending messages, not deleting it",
+                    self.consumer_name
+                );
+                return;
+            }
+            Err(e) => {
+                error!("Error while fetching pending messages: {}", e);
+                return;
+            }
+        }
+
         let res1: redis::RedisResult<Value> = self.conn.xgroup_delconsumer(
             FABSEAL_SUBMISSION_QUEUE,
             FABSEAL_SUBMISSION_CONSUMER_GROUP,
@@ -61,12 +246,17 @@ impl Drop for Worker {
 }
 
 impl Worker {
-    pub(crate) fn create(settings: Settings) -> Result<Worker> {
-        let ctx = WorkerContext::from_dmstl_dir(&settings.dmstl_directory)?;
+    pub(crate) fn create(settings: Settings, shutdown: &Shutdown) -> Result<Worker> {
+        let backend = create_backend(&settings)?;
+        info!(
+            "using mesh backend {} ({})",
+            backend.name(),
+            backend.version()
+        );
 
-        let redis_addr = format!("redis://{}/", settings.redis.address);
+        let connection_info = connection_info(&settings)?;
 
-        Self::create_from(settings, ctx, &redis_addr)
+        Self::create_from(settings, backend, shutdown, connection_info)
     }
 
     fn setup_stream(redis_conn: &mut redis::Connection) -> redis::RedisResult<()> {
@@ -126,25 +316,30 @@ impl Worker {
         format!("fs_consumer_{:08X}", id)
     }
 
-    fn setup_exit_flag() -> Result<Arc<AtomicBool>> {
-        let should_exit = Arc::new(AtomicBool::new(false));
-        signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&should_exit))?;
-        signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&should_exit))?;
-        Ok(should_exit)
-    }
-
-    fn create_from(settings: Settings, ctx: WorkerContext, redis_addr: &str) -> Result<Worker> {
-        let client = redis::Client::open(redis_addr)?;
+    fn create_from(
+        settings: Settings,
+        backend: Box<dyn MeshBackend>,
+        shutdown: &Shutdown,
+        connection_info: ConnectionInfo,
+    ) -> Result<Worker> {
+        let client = redis::Client::open(connection_info)?;
         let conn = {
             let mut conn = client.get_connection()?;
             Self::setup_stream(&mut conn)?;
             conn
         };
 
-        let should_exit = Self::setup_exit_flag()?;
-
         let consumer_name = Self::consumer_name();
 
+        let heartbeat = Heartbeat::start(
+            &client,
+            WorkerInfo {
+                consumer_name: consumer_name.clone(),
+                backend: backend.name(),
+                backend_version: backend.version(),
+            },
+        )?;
+
         const READ_TIMEOUT: usize = 1000;
         let read_options = StreamReadOptions::default()
             .count(1)
@@ -154,10 +349,13 @@ impl Worker {
         Ok(Worker {
             settings,
             conn,
-            ctx,
-            should_exit,
+            backend,
+            should_exit: Arc::clone(&shutdown.should_exit),
+            should_abort: Arc::clone(&shutdown.should_abort),
             read_options,
             consumer_name,
+            last_reclaim: Instant::now(),
+            heartbeat,
         })
     }
 
@@ -168,9 +366,226 @@ impl Worker {
         Ok(results)
     }
 
+    /// Claims messages that were delivered to other consumers, but have not been acknowledged for too long
+    fn reclaim_pending(&mut self) -> Result<()> {
+        let min_idle_ms = self.settings.reclaim.min_idle_ms;
+
+        // Only stale messages, so that they are found however many jobs are in progress
+        let pending: StreamPendingCountReply = redis::cmd("XPENDING")
+            .arg(FABSEAL_SUBMISSION_QUEUE)
+            .arg(FABSEAL_SUBMISSION_CONSUMER_GROUP)
+            .arg("IDLE")
+            .arg(min_idle_ms)
+            .arg("-")
+            .arg("+")
+            .arg(RECLAIM_BATCH)
+            .query(&mut self.conn)?;
+
+        for p in pending.ids {
+            info!(
+                "claiming message {} (consumer {}, idle {} ms, delivered {} times)",
+                p.id, p.consumer, p.last_delivered_ms, p.times_delivered
+            );
+
+            let claimed: StreamClaimReply = match self.conn.xclaim(
+                FABSEAL_SUBMISSION_QUEUE,
+                FABSEAL_SUBMISSION_CONSUMER_GROUP,
+                &self.consumer_name,
+                min_idle_ms,
+                &[&p.id],
+            ) {
+                Ok(claimed) => claimed,
+                Err(e) => {
+                    // Older Redis versions keep pending entries for deleted messages, drop them
+                    let existing: StreamRangeReply =
+                        self.conn
+                            .xrange_count(FABSEAL_SUBMISSION_QUEUE, &p.id, &p.id, 1)?;
+                    if existing.ids.is_empty() {
+                        warn!("message {} no longer exists, acknowledging it", p.id);
+                        let _: Value = self.conn.xack(
+                            FABSEAL_SUBMISSION_QUEUE,
+                            FABSEAL_SUBMISSION_CONSUMER_GROUP,
+                            &[&p.id],
+                        )?;
+                        continue;
+                    }
+                    return Err(e.into());
+                }
+            };
+
+            for msg in claimed.ids {
+                if p.times_delivered >= self.settings.reclaim.max_deliveries {
+                    self.handle_undeliverable_msg(msg, p.times_delivered);
+                } else {
+                    self.handle_stream_msg(msg);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes consumers of crashed workers from the group, once their messages were reclaimed
+    ///
+    /// Running workers read from the stream every second, so consumers idle for longer than
+    /// `reclaim.min_idle_ms` are gone. Consumers with pending messages are kept, as removing
+    /// a consumer drops its pending messages.
+    fn remove_dead_consumers(&mut self) -> Result<()> {
+        let min_idle_ms = self.settings.reclaim.min_idle_ms;
+
+        let reply: StreamInfoConsumersReply = self
+            .conn
+            .xinfo_consumers(FABSEAL_SUBMISSION_QUEUE, FABSEAL_SUBMISSION_CONSUMER_GROUP)?;
+
+        for consumer in reply.consumers {
+            if consumer.pending > 0
+                || (consumer.idle as u64) < min_idle_ms
+                || consumer.name == self.consumer_name
+            {
+                continue;
+            }
+
+            info!(
+                "removing consumer {} (idle {} ms)",
+                consumer.name, consumer.idle
+            );
+            let _: Value = redis::cmd("XGROUP")
+                .arg("DELCONSUMER")
+                .arg(FABSEAL_SUBMISSION_QUEUE)
+                .arg(FABSEAL_SUBMISSION_CONSUMER_GROUP)
+                .arg(&consumer.name)
+                .query(&mut self.conn)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_undeliverable_msg(&mut self, msg: StreamId, times_delivered: usize) {
+        warn!(
+            "message {} was delivered {} times, giving up",
+            msg.id, times_delivered
+        );
+
+        let error = format!("Job failed after {} delivery attempts", times_delivered);
+        self.fail_msg(msg, &error, times_delivered.try_into().unwrap_or(u32::MAX));
+    }
+
+    /// Moves a message to the dead-letter stream, marks its job as failed and acknowledges it
+    ///
+    /// If the message cannot be moved, it stays pending and is picked up again by `reclaim_pending`.
+    fn fail_msg(&mut self, msg: StreamId, error: &str, attempts: u32) {
+        self.heartbeat.job_failed();
+        metrics::JOBS.with_label_values(&["failed"]).inc();
+
+        if let Some(request_id) = Self::parse_request_id(&msg) {
+            self.set_status(
+                request_id,
+                JobState::Failed,
+                &[
+                    ("finished_at", unix_timestamp().to_string()),
+                    ("error", error.to_string()),
+                ],
+            );
+        }
+
+        match dead_letter::add(
+            &mut self.conn,
+            &msg,
+            error,
+            attempts,
+            self.settings.retry.dead_letter_limit,
+        ) {
+            Ok(()) => self.send_ack(msg),
+            Err(e) => {
+                error!(
+                    "error while moving message {} to the dead-letter stream, keeping it pending: {}",
+                    msg.id, e
+                );
+                metrics::REDIS_ERRORS
+                    .with_label_values(&["dead_letter"])
+                    .inc();
+            }
+        }
+    }
+
+    /// Claims a message that is still pending for this consumer again, resetting its idle time,
+    /// so that other workers do not reclaim it while it is retried
+    ///
+    /// Returns `false` if the message is no longer pending for this consumer.
+    fn renew_claim(&mut self, msg: &StreamId) -> bool {
+        let pending: redis::RedisResult<StreamPendingCountReply> =
+            self.conn.xpending_consumer_count(
+                FABSEAL_SUBMISSION_QUEUE,
+                FABSEAL_SUBMISSION_CONSUMER_GROUP,
+                &msg.id,
+                &msg.id,
+                1,
+                &self.consumer_name,
+            );
+        let claimed: redis::RedisResult<Vec<String>> = match pending {
+            Ok(reply) if reply.ids.is_empty() => return false,
+            Ok(_) => self.conn.xclaim_options(
+                FABSEAL_SUBMISSION_QUEUE,
+                FABSEAL_SUBMISSION_CONSUMER_GROUP,
+                &self.consumer_name,
+                0,
+                &[&msg.id],
+                StreamClaimOptions::default().with_justid(),
+            ),
+            Err(e) => Err(e),
+        };
+
+        match claimed {
+            Ok(ids) => !ids.is_empty(),
+            Err(e) => {
+                // Retrying is still better than giving up, the message might be reclaimed though
+                error!("error while renewing claim of message {}: {}", msg.id, e);
+                metrics::REDIS_ERRORS
+                    .with_label_values(&["renew_claim"])
+                    .inc();
+                true
+            }
+        }
+    }
+
+    /// Sleeps for the given duration, returns `false` if the worker should exit instead
+    fn sleep_unless_exit(&self, duration: Duration) -> bool {
+        const STEP: Duration = Duration::from_millis(100);
+
+        let start = Instant::now();
+        while !self.should_exit.load(Ordering::Relaxed) {
+            let elapsed = start.elapsed();
+            if elapsed >= duration {
+                return true;
+            }
+            std::thread::sleep(STEP.min(duration - elapsed));
+        }
+        false
+    }
+
     pub(crate) fn run(&mut self) {
+        let reclaim_interval = Duration::from_secs(self.settings.reclaim.interval);
+
         while !self.should_exit.load(Ordering::Relaxed) {
-            match self.read_stream() {
+            if self.last_reclaim.elapsed() >= reclaim_interval {
+                self.last_reclaim = Instant::now();
+                if let Err(e) = self.reclaim_pending() {
+                    error!("error while reclaiming pending messages: {:?}", e);
+                    metrics::REDIS_ERRORS.with_label_values(&["reclaim"]).inc();
+                }
+                if let Err(e) = self.remove_dead_consumers() {
+                    error!("error while removing dead consumers: {:?}", e);
+                    metrics::REDIS_ERRORS
+                        .with_label_values(&["remove_consumers"])
+                        .inc();
+                }
+            }
+
+            let read_start = Instant::now();
+            let results = self.read_stream();
+            metrics::READ_STREAM_BLOCKED.inc_by(read_start.elapsed().as_secs_f64());
+
+            match results {
                 Ok(results) => {
                     for sk in results.keys {
                         debug_assert!(sk.key == FABSEAL_SUBMISSION_QUEUE);
@@ -181,113 +596,269 @@ impl Worker {
                 }
                 Err(e) => {
                     error!("redis error, ignoring message: {:?}", e);
+                    metrics::REDIS_ERRORS
+                        .with_label_values(&["read_stream"])
+                        .inc();
                 }
             }
         }
 
-        info!("received signal, shutting down");
+        info!("consumer {} shutting down", self.consumer_name);
     }
 
     fn send_ack(&mut self, msg: StreamId) {
         debug!("ack-ing message");
 
-        let resp: Value = self
-            .conn
-            .xack(
-                FABSEAL_SUBMISSION_QUEUE,
-                FABSEAL_SUBMISSION_CONSUMER_GROUP,
-                &[msg.id],
-            )
-            .unwrap();
+        let resp: redis::RedisResult<Value> = self.conn.xack(
+            FABSEAL_SUBMISSION_QUEUE,
+            FABSEAL_SUBMISSION_CONSUMER_GROUP,
+            &[&msg.id],
+        );
         debug!("resp: {:?}", resp);
 
-        debug_assert!(resp == redis::Value::Int(1));
-        if resp != redis::Value::Int(1) {
-            error!("error while sending XACK, response: {:?}", resp);
+        match resp {
+            Ok(redis::Value::Int(1)) => {}
+            Ok(resp) => error!("error while sending XACK, response: {:?}", resp),
+            Err(e) => {
+                // The message stays pending, so it is processed again once another worker reclaims it
+                error!(
+                    "error while acknowledging message {}, leaving it pending: {}",
+                    msg.id, e
+                );
+                metrics::REDIS_ERRORS.with_label_values(&["ack"]).inc();
+            }
+        }
+    }
+
+    fn set_status(&mut self, request_id: RequestId, state: JobState, fields: &[(&str, String)]) {
+        let key = status_key(request_id);
+        trace!("setting status key={} state={}", key, state);
+
+        let mut items = vec![("state", state.as_str().to_string())];
+        items.extend(fields.iter().map(|(k, v)| (*k, v.clone())));
+
+        let res: redis::RedisResult<()> = self.conn.hset_multiple(&key, &items).and_then(|()| {
+            self.conn
+                .expire(&key, self.settings.limits.session_ttl.try_into().unwrap())
+        });
+        if let Err(e) = res {
+            error!(
+                "error while updating status of request {}: {}",
+                request_id, e
+            );
+            metrics::REDIS_ERRORS
+                .with_label_values(&["set_status"])
+                .inc();
+        }
+    }
+
+    /// Stores the output of the mesh backend, so that failed jobs can be debugged via the admin API
+    fn store_log(&mut self, request_id: RequestId, log: Vec<u8>) {
+        let key = log_key(request_id);
+        trace!("setting log key={} len={}", key, log.len());
+
+        let res: redis::RedisResult<()> = self.conn.set_ex(
+            &key,
+            log,
+            self.settings.limits.result_ttl.try_into().unwrap(),
+        );
+        if let Err(e) = res {
+            error!("error while storing log of request {}: {}", request_id, e);
+            metrics::REDIS_ERRORS
+                .with_label_values(&["store_log"])
+                .inc();
         }
     }
 
+    fn parse_request_id(msg: &StreamId) -> Option<RequestId> {
+        match msg.map.get("request_id") {
+            Some(Value::Data(v)) => RequestId::try_from(v.as_slice()).ok(),
+            _ => None,
+        }
+    }
 
     fn handle_stream_msg(&mut self, msg: StreamId) {
+        self.heartbeat
+            .set_current_request(Self::parse_request_id(&msg));
+        self.process_stream_msg(msg);
+        self.heartbeat.set_current_request(None);
+    }
+
+    fn process_stream_msg(&mut self, msg: StreamId) {
         trace!("msg={:?}", msg);
 
-        let request_id: RequestId = match msg.map.get("request_id").unwrap() {
-            Value::Data(v) => {
-                debug_assert_eq!(v.len(), 4);
-                RequestId::try_from(v.as_slice()).unwrap()
-            }
-            _ => {
-                panic!("panic");
+        let request_id: RequestId = match Self::parse_request_id(&msg) {
+            Some(request_id) => request_id,
+            None => {
+                error!("malformed message {}, dropping it: {:?}", msg.id, msg.map);
+                self.fail_msg(msg, "Malformed message", 0);
+                return;
             }
         };
 
         debug!("received message with request_id={}", request_id);
 
-        let image_data: Vec<u8> = match self.conn.get(processed_image_key(request_id)).unwrap() {
-            Value::Data(v) => v,
-            Value::Nil => {
-                warn!("Tried reading image for request {}, but it is gone (probably expired)", request_id);
-
-                self.send_ack(msg);
-                return;
-            },
-            v => {
-                error!("unexpected Redis response value: {:?}", v);
-                panic!("panic");
+        let request_settings: Option<RequestSettings> = match msg.map.get("settings") {
+            Some(Value::Data(v)) => match serde_json::from_slice(v) {
+                Ok(request_settings) => Some(request_settings),
+                Err(e) => {
+                    warn!(
+                        "Invalid settings for request {}, using defaults: {}",
+                        request_id, e
+                    );
+                    None
+                }
             },
+            _ => None,
         };
+        debug!("request settings: {:?}", request_settings);
 
-        match self.try_handle(&image_data, request_id) {
-            Ok(_) => {
-                debug!("ack-ing message");
+        self.set_status(
+            request_id,
+            JobState::Processing,
+            &[("started_at", unix_timestamp().to_string())],
+        );
 
-                self.send_ack(msg);
-            }
-            Err(e) => {
-                error!("error while processing: {}", e);
+        let max_attempts = self.settings.retry.max_attempts.max(1);
+        let mut attempt: u32 = 0;
+        let error = loop {
+            attempt += 1;
+
+            match self.try_attempt(request_id, request_settings.as_ref()) {
+                Ok(Some(metadata)) => {
+                    self.set_status(
+                        request_id,
+                        JobState::Done,
+                        &[
+                            ("finished_at", unix_timestamp().to_string()),
+                            ("backend", metadata.backend.to_string()),
+                            ("duration_ms", metadata.duration.as_millis().to_string()),
+                        ],
+                    );
+                    self.heartbeat.job_done();
+                    metrics::JOBS.with_label_values(&["done"]).inc();
+                    self.send_ack(msg);
+                    return;
+                }
+                Ok(None) => {
+                    warn!(
+                        "Tried reading image for request {}, but it is gone (probably expired)",
+                        request_id
+                    );
 
-                self.send_ack(msg);
+                    self.set_status(
+                        request_id,
+                        JobState::Expired,
+                        &[("finished_at", unix_timestamp().to_string())],
+                    );
+                    metrics::JOBS.with_label_values(&["expired"]).inc();
+                    self.send_ack(msg);
+                    return;
+                }
+                Err(e) => match e.downcast_ref::<JobAborted>() {
+                    Some(JobAborted::Interrupted) => {
+                        // Leave the message pending, another worker will reclaim it
+                        info!("shutting down, leaving message {} pending", msg.id);
+                        self.set_status(request_id, JobState::Queued, &[]);
+                        return;
+                    }
+                    Some(JobAborted::TimedOut(_)) => {
+                        // Don't retry, a hung job would most likely hang again
+                        error!("error while processing, giving up: {}", e);
+                        break e;
+                    }
+                    None if attempt >= max_attempts => {
+                        error!(
+                            "error while processing (attempt {}/{}), giving up: {}",
+                            attempt, max_attempts, e
+                        );
+                        break e;
+                    }
+                    None => {
+                        let backoff = self.settings.retry.backoff(attempt);
+                        warn!(
+                            "error while processing (attempt {}/{}), retrying in {:?}: {}",
+                            attempt, max_attempts, backoff, e
+                        );
+                        if !self.sleep_unless_exit(backoff) {
+                            // Leave the message pending, another worker will reclaim it
+                            info!("shutting down, not retrying message {}", msg.id);
+                            return;
+                        }
+                        if !self.renew_claim(&msg) {
+                            warn!(
+                                "message {} was claimed by another worker, not retrying it",
+                                msg.id
+                            );
+                            return;
+                        }
+                    }
+                },
             }
         };
+
+        self.fail_msg(msg, &error.to_string(), attempt);
     }
 
-    fn try_handle(&mut self, payload: &[u8], request_id: RequestId) -> Result<()> {
-        let fctx = CommandFileContext::create(payload)?;
-
-        let mut comm = Command::new("blender");
-
-        comm.stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .arg("--background")
-            .arg(self.ctx.blend_path.as_os_str())
-            .arg("--python")
-            .arg(self.ctx.python_path.as_os_str())
-            .arg("--log-level")
-            .arg("0")
-            .arg("--")
-            .arg(fctx.input_file_path())
-            .arg(fctx.output_file_path());
-
-        debug!("the command: {:?}", comm);
-
-        let status = comm.status()?;
-        if !status.success() {
-            trace!("explicitly closing temporary files");
-            drop(fctx);
-            color_eyre::eyre::bail!("Blender command failed");
+    /// Processes the image of a request once
+    ///
+    /// Returns `None` if the image is gone.
+    fn try_attempt(
+        &mut self,
+        request_id: RequestId,
+        request_settings: Option<&RequestSettings>,
+    ) -> Result<Option<MeshMetadata>> {
+        let image_data: Option<Vec<u8>> = self.conn.get(processed_image_key(request_id))?;
+        match image_data {
+            Some(image_data) => {
+                let metadata = self.try_handle(&image_data, request_id, request_settings)?;
+                Ok(Some(metadata))
+            }
+            None => Ok(None),
         }
+    }
 
-        let result_data = fctx.finish()?;
-        debug!("result sz={}", result_data.len());
+    fn try_handle(
+        &mut self,
+        payload: &[u8],
+        request_id: RequestId,
+        request_settings: Option<&RequestSettings>,
+    ) -> Result<MeshMetadata> {
+        let heightmap = match request_settings {
+            Some(request_settings) => {
+                Cow::Owned(heightmap::apply_settings(payload, request_settings)?)
+            }
+            None => Cow::Borrowed(payload),
+        };
+
+        let control = JobControl::new(
+            Duration::from_secs(self.settings.job.timeout),
+            Arc::clone(&self.should_abort),
+        );
+        let mesh_start = Instant::now();
+        let output = self.backend.mesh(&heightmap, request_settings, &control);
+        metrics::MESH_DURATION
+            .with_label_values(&[self.backend.name()])
+            .observe(mesh_start.elapsed().as_secs_f64());
+        if let Some(log) = self.backend.take_log() {
+            self.store_log(request_id, log);
+        }
+        let output = output?;
+        debug!(
+            "result sz={} metadata={:?}",
+            output.model.len(),
+            output.metadata
+        );
+        metrics::RESULT_SIZE.observe(output.model.len() as f64);
 
         let key = result_key(request_id);
         trace!("setting key={}", key);
         let _: () = self.conn.set_ex(
             key,
-            result_data,
+            output.model,
             self.settings.limits.result_ttl.try_into().unwrap(),
         )?;
 
-        Ok(())
+        Ok(output.metadata)
     }
 }
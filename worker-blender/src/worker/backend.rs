@@ -0,0 +1,132 @@
+use std::{
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, Result};
+
+use fabseal_micro_common::RequestSettings;
+
+use crate::settings::{BackendKind, Settings};
+
+mod blender;
+pub(crate) use blender::BlenderBackend;
+mod fake;
+pub(crate) use fake::FakeBackend;
+mod native;
+pub(crate) use native::NativeBackend;
+
+/// Information about how a model was generated
+#[derive(Debug, Clone)]
+pub(crate) struct MeshMetadata {
+    pub(crate) backend: &'static str,
+    pub(crate) duration: Duration,
+}
+
+pub(crate) struct MeshOutput {
+    /// The generated model (binary STL)
+    pub(crate) model: Vec<u8>,
+    pub(crate) metadata: MeshMetadata,
+}
+
+/// Reason for aborting a running job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JobAborted {
+    /// The job took longer than the configured timeout
+    TimedOut(Duration),
+    /// The worker is shutting down
+    Interrupted,
+}
+
+impl fmt::Display for JobAborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobAborted::TimedOut(timeout) => {
+                write!(f, "Job timed out after {} seconds", timeout.as_secs())
+            }
+            JobAborted::Interrupted => f.write_str("Job interrupted by worker shutdown"),
+        }
+    }
+}
+
+impl Error for JobAborted {}
+
+/// Deadline and shutdown flag of a running job
+pub(crate) struct JobControl {
+    timeout: Duration,
+    deadline: Instant,
+    should_exit: Arc<AtomicBool>,
+}
+
+impl JobControl {
+    pub(crate) fn new(timeout: Duration, should_exit: Arc<AtomicBool>) -> JobControl {
+        JobControl {
+            timeout,
+            deadline: Instant::now() + timeout,
+            should_exit,
+        }
+    }
+
+    /// Returns an error if the job should be aborted
+    pub(crate) fn check(&self) -> Result<(), JobAborted> {
+        if self.should_exit.load(Ordering::Relaxed) {
+            Err(JobAborted::Interrupted)
+        } else if Instant::now() >= self.deadline {
+            Err(JobAborted::TimedOut(self.timeout))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Turns a heightmap into a 3D model
+///
+/// Backends do not know about Redis: the worker fetches the heightmap, applies the model options
+/// from the request settings and stores the result.
+pub(crate) trait MeshBackend: Send {
+    fn name(&self) -> &'static str;
+
+    /// Version of the backend, published in the worker heartbeat
+    fn version(&self) -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+
+    /// Verifies that the backend can run jobs (used by `--check`)
+    fn check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// `heightmap` is an 8-bit grayscale PNG, `settings` are absent for old submissions
+    ///
+    /// Long-running backends should regularly call `control.check()` and abort with its error.
+    fn mesh(
+        &mut self,
+        heightmap: &[u8],
+        settings: Option<&RequestSettings>,
+        control: &JobControl,
+    ) -> Result<MeshOutput>;
+
+    /// Output of the last call to `mesh` (successful or not), for debugging failed jobs
+    fn take_log(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+pub(crate) fn create_backend(settings: &Settings) -> Result<Box<dyn MeshBackend>> {
+    match settings.backend {
+        BackendKind::Blender => {
+            let dmstl_directory = settings
+                .dmstl_directory
+                .as_ref()
+                .ok_or_else(|| eyre!("The Blender backend requires dmstl_directory to be set"))?;
+            Ok(Box::new(BlenderBackend::create(dmstl_directory)?))
+        }
+        BackendKind::Native => Ok(Box::new(NativeBackend::create(&settings.native)?)),
+        BackendKind::Fake => Ok(Box::new(FakeBackend)),
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    io::{Read, Write},
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, trace, warn};
+
+use color_eyre::eyre::{eyre, Result};
+
+use fabseal_micro_common::RequestSettings;
+
+use crate::worker::{
+    backend::{JobControl, MeshBackend, MeshMetadata, MeshOutput},
+    file_context::CommandFileContext,
+    util::WorkerContext,
+};
+
+/// Runs displacementMapToStl in a Blender subprocess
+pub(crate) struct BlenderBackend {
+    ctx: WorkerContext,
+    version: String,
+    log: Option<Vec<u8>>,
+}
+
+/// Number of bytes kept of each output stream of Blender (the end of the output is kept)
+const LOG_LIMIT: usize = 64 * 1024;
+
+/// Tail of an output stream
+struct CapturedOutput {
+    data: Vec<u8>,
+    omitted: usize,
+}
+
+/// Reads a stream until EOF in a separate thread, so that Blender never blocks on a full pipe
+fn capture<R>(mut reader: R) -> JoinHandle<CapturedOutput>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut output = CapturedOutput {
+            data: Vec::new(),
+            omitted: 0,
+        };
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("error while reading Blender output: {}", e);
+                    break;
+                }
+            };
+            output.data.extend_from_slice(&buf[..n]);
+            // Only trim once in a while to avoid moving the data on every read
+            if output.data.len() > 2 * LOG_LIMIT {
+                output.trim();
+            }
+        }
+        output.trim();
+        output
+    })
+}
+
+impl CapturedOutput {
+    fn trim(&mut self) {
+        if self.data.len() > LOG_LIMIT {
+            let excess = self.data.len() - LOG_LIMIT;
+            self.data.drain(..excess);
+            self.omitted += excess;
+        }
+    }
+
+    fn write_to(&self, name: &str, log: &mut Vec<u8>) {
+        let _ = writeln!(log, "--- {} ---", name);
+        if self.omitted > 0 {
+            let _ = writeln!(log, "[{} bytes omitted]", self.omitted);
+        }
+        log.extend_from_slice(&self.data);
+        if !self.data.ends_with(b"\n") {
+            log.push(b'\n');
+        }
+    }
+}
+
+/// Combines the captured output streams into one log
+fn collect_log(
+    stdout: JoinHandle<CapturedOutput>,
+    stderr: JoinHandle<CapturedOutput>,
+    status: Option<&ExitStatus>,
+) -> Vec<u8> {
+    let mut log = Vec::new();
+    for (name, handle) in [("stdout", stdout), ("stderr", stderr)] {
+        match handle.join() {
+            Ok(output) => output.write_to(name, &mut log),
+            Err(_) => error!("thread capturing Blender {} panicked", name),
+        }
+    }
+    match status {
+        Some(status) => {
+            let _ = writeln!(log, "--- {} ---", status);
+        }
+        None => {
+            let _ = writeln!(log, "--- killed ---");
+        }
+    }
+    log
+}
+
+/// Kills the process group of Blender if it is still running when dropped
+/// (e.g. if the worker thread panics), so that Blender is not left behind as an orphan
+struct RunningBlender(Child);
+
+impl Drop for RunningBlender {
+    fn drop(&mut self) {
+        if let Ok(None) = self.0.try_wait() {
+            warn!("Blender (pid {}) is still running, killing it", self.0.id());
+            BlenderBackend::kill(&mut self.0);
+        }
+    }
+}
+
+/// Interval for checking whether Blender has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl BlenderBackend {
+    pub(crate) fn create(dmstl_directory: &Path) -> Result<BlenderBackend> {
+        let ctx = WorkerContext::from_dmstl_dir(dmstl_directory)?;
+        Ok(BlenderBackend {
+            ctx,
+            version: Self::blender_version().unwrap_or_else(|e| {
+                warn!("could not determine Blender version: {}", e);
+                "unknown".to_string()
+            }),
+            log: None,
+        })
+    }
+
+    /// First line of `blender --version`, e.g. "Blender 2.93.1"
+    fn blender_version() -> Result<String> {
+        let output = Command::new("blender")
+            .env_remove("SSL_CERT_FILE")
+            .arg("--version")
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .map_err(|e| eyre!("error while running blender --version: {}", e))?;
+        if !output.status.success() {
+            color_eyre::eyre::bail!("blender --version failed ({})", output.status);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            .unwrap_or("unknown")
+            .trim()
+            .to_string())
+    }
+
+    /// Waits for Blender to exit, kills it if the job is aborted
+    fn wait(child: &mut Child, control: &JobControl) -> Result<ExitStatus> {
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+
+            if let Err(aborted) = control.check() {
+                warn!("{}, killing Blender (pid {})", aborted, child.id());
+                Self::kill(child);
+                return Err(aborted.into());
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Kills the process group of Blender, including processes spawned by it
+    fn kill(child: &mut Child) {
+        // Blender is the leader of its own process group, see `mesh`
+        let pgid = child.id() as libc::pid_t;
+        // SAFETY: killpg has no memory safety requirements
+        if unsafe { libc::killpg(pgid, libc::SIGKILL) } != 0 {
+            warn!(
+                "killpg failed ({}), killing Blender only",
+                std::io::Error::last_os_error()
+            );
+            let _ = child.kill();
+        }
+        if let Err(e) = child.wait() {
+            warn!("error while waiting for killed Blender process: {}", e);
+        }
+    }
+}
+
+impl MeshBackend for BlenderBackend {
+    fn name(&self) -> &'static str {
+        "blender"
+    }
+
+    fn mesh(
+        &mut self,
+        heightmap: &[u8],
+        _settings: Option<&RequestSettings>,
+        control: &JobControl,
+    ) -> Result<MeshOutput> {
+        let start = Instant::now();
+
+        let fctx = CommandFileContext::create(heightmap)?;
+
+        let mut comm = Command::new("blender");
+
+        comm.stdin(Stdio::null())
+            // Might point to the CA bundle for Redis, see `trust_ca_file`
+            .env_remove("SSL_CERT_FILE")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("--background")
+            .arg(self.ctx.blend_path.as_os_str())
+            .arg("--python")
+            .arg(self.ctx.python_path.as_os_str())
+            .arg("--log-level")
+            .arg("0")
+            .arg("--")
+            .arg(fctx.input_file_path())
+            .arg(fctx.output_file_path())
+            // Own process group, so that a hung Blender can be killed with all its children
+            .process_group(0);
+
+        debug!("the command: {:?}", comm);
+
+        self.log = None;
+        let mut child = RunningBlender(comm.spawn()?);
+        let stdout = capture(
+            child
+                .0
+                .stdout
+                .take()
+                .ok_or_else(|| eyre!("Blender stdout not captured"))?,
+        );
+        let stderr = capture(
+            child
+                .0
+                .stderr
+                .take()
+                .ok_or_else(|| eyre!("Blender stderr not captured"))?,
+        );
+
+        let status = Self::wait(&mut child.0, control);
+        self.log = Some(collect_log(stdout, stderr, status.as_ref().ok()));
+
+        let status = status?;
+        if !status.success() {
+            trace!("explicitly closing temporary files");
+            drop(fctx);
+            color_eyre::eyre::bail!("Blender command failed ({})", status);
+        }
+
+        let model = fctx.finish()?;
+
+        Ok(MeshOutput {
+            model,
+            metadata: MeshMetadata {
+                backend: self.name(),
+                duration: start.elapsed(),
+            },
+        })
+    }
+
+    fn version(&self) -> String {
+        self.version.clone()
+    }
+
+    fn check(&self) -> Result<()> {
+        for path in &[&self.ctx.blend_path, &self.ctx.python_path] {
+            if !path.is_file() {
+                color_eyre::eyre::bail!("{} does not exist", path.display());
+            }
+        }
+        let version = Self::blender_version()?;
+        info!("found {}", version);
+        Ok(())
+    }
+
+    fn take_log(&mut self) -> Option<Vec<u8>> {
+        self.log.take()
+    }
+}
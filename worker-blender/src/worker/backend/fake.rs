@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+
+use fabseal_micro_common::RequestSettings;
+
+use crate::worker::backend::{JobControl, MeshBackend, MeshMetadata, MeshOutput};
+
+/// Header of binary STL files (80 bytes)
+const STL_HEADER: &[u8; 80] =
+    b"fabseal fake backend                                                            ";
+
+/// A unit tetrahedron (normal, three vertices)
+const TETRAHEDRON: [[[f32; 3]; 4]; 4] = [
+    [
+        [0.0, 0.0, -1.0],
+        [0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [1.0, 0.0, 0.0],
+    ],
+    [
+        [0.0, -1.0, 0.0],
+        [0.0, 0.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0],
+    ],
+    [
+        [-1.0, 0.0, 0.0],
+        [0.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0],
+        [0.0, 1.0, 0.0],
+    ],
+    [
+        [0.577_350_3, 0.577_350_3, 0.577_350_3],
+        [1.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [0.0, 0.0, 1.0],
+    ],
+];
+
+/// Deterministic backend that ignores its input and returns a tetrahedron
+///
+/// Useful to exercise the Redis stream plumbing without Blender.
+pub(crate) struct FakeBackend;
+
+impl MeshBackend for FakeBackend {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    fn mesh(
+        &mut self,
+        _heightmap: &[u8],
+        _settings: Option<&RequestSettings>,
+        _control: &JobControl,
+    ) -> Result<MeshOutput> {
+        let mut model = Vec::with_capacity(84 + 50 * TETRAHEDRON.len());
+        model.extend_from_slice(STL_HEADER);
+        model.extend_from_slice(&(TETRAHEDRON.len() as u32).to_le_bytes());
+        for triangle in TETRAHEDRON.iter() {
+            for v in triangle.iter().flatten() {
+                model.extend_from_slice(&v.to_le_bytes());
+            }
+            // Attribute byte count
+            model.extend_from_slice(&0u16.to_le_bytes());
+        }
+
+        Ok(MeshOutput {
+            model,
+            metadata: MeshMetadata {
+                backend: self.name(),
+                duration: Duration::from_secs(0),
+            },
+        })
+    }
+}
@@ -0,0 +1,347 @@
+use std::time::Instant;
+
+use image::{imageops, GrayImage, ImageFormat};
+
+use log::debug;
+
+use color_eyre::eyre::{ensure, Result};
+
+use fabseal_micro_common::RequestSettings;
+
+use crate::{
+    settings::NativeMesherSettings,
+    worker::backend::{JobControl, MeshBackend, MeshMetadata, MeshOutput},
+};
+
+type Vertex = [f32; 3];
+
+/// Writes a binary STL file
+struct StlWriter {
+    data: Vec<u8>,
+    triangles: u32,
+}
+
+impl StlWriter {
+    const HEADER: &'static [u8] = b"fabseal native mesher";
+
+    fn with_capacity(triangles: usize) -> Self {
+        let mut data = Vec::with_capacity(84 + 50 * triangles);
+        data.extend_from_slice(Self::HEADER);
+        data.resize(80, b' ');
+        // Triangle count, filled in by finish()
+        data.extend_from_slice(&0u32.to_le_bytes());
+        StlWriter { data, triangles: 0 }
+    }
+
+    /// Adds a triangle, with vertices in counter-clockwise order seen from outside
+    fn triangle(&mut self, a: Vertex, b: Vertex, c: Vertex) {
+        let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+        let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+        let n = [
+            u[1] * v[2] - u[2] * v[1],
+            u[2] * v[0] - u[0] * v[2],
+            u[0] * v[1] - u[1] * v[0],
+        ];
+        let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+        let n = if len > 0.0 {
+            [n[0] / len, n[1] / len, n[2] / len]
+        } else {
+            [0.0, 0.0, 0.0]
+        };
+
+        for v in [n, a, b, c].iter().flatten() {
+            self.data.extend_from_slice(&v.to_le_bytes());
+        }
+        // Attribute byte count
+        self.data.extend_from_slice(&0u16.to_le_bytes());
+        self.triangles += 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.data[80..84].copy_from_slice(&self.triangles.to_le_bytes());
+        self.data
+    }
+}
+
+/// Meshes heightmaps in-process, without Blender
+///
+/// The model consists of the relief on top, vertical side walls and a flat base,
+/// so the result is watertight and can be printed directly.
+pub(crate) struct NativeBackend {
+    settings: NativeMesherSettings,
+}
+
+impl NativeBackend {
+    pub(crate) fn create(settings: &NativeMesherSettings) -> Result<NativeBackend> {
+        ensure!(
+            settings.size_mm > 0.0,
+            "native.size_mm must be positive, got {}",
+            settings.size_mm
+        );
+        ensure!(
+            settings.relief_mm >= 0.0 && settings.base_mm > 0.0,
+            "native.relief_mm must not be negative and native.base_mm must be positive"
+        );
+        ensure!(
+            settings.max_resolution >= 2,
+            "native.max_resolution must be at least 2"
+        );
+        Ok(NativeBackend {
+            settings: settings.clone(),
+        })
+    }
+
+    fn load_heightmap(&self, heightmap: &[u8]) -> Result<GrayImage> {
+        let img = image::load_from_memory_with_format(heightmap, ImageFormat::Png)?.to_luma8();
+
+        let (width, height) = img.dimensions();
+        ensure!(
+            width >= 2 && height >= 2,
+            "heightmap too small ({}x{})",
+            width,
+            height
+        );
+
+        let longer = width.max(height);
+        if longer <= self.settings.max_resolution {
+            return Ok(img);
+        }
+
+        let scale = self.settings.max_resolution as f32 / longer as f32;
+        let width = ((width as f32 * scale).round() as u32).max(2);
+        let height = ((height as f32 * scale).round() as u32).max(2);
+        debug!("downscaling heightmap to {}x{}", width, height);
+        Ok(imageops::resize(
+            &img,
+            width,
+            height,
+            imageops::FilterType::Triangle,
+        ))
+    }
+
+    fn build(&self, img: &GrayImage) -> Vec<u8> {
+        let (width, height) = img.dimensions();
+        let (w, h) = (width as usize, height as usize);
+
+        let step = self.settings.size_mm / (w.max(h) - 1) as f32;
+        let relief = self.settings.relief_mm / 255.0;
+        let base = self.settings.base_mm;
+
+        // Image rows go downwards, the model's y axis goes upwards
+        let top = |i: usize, j: usize| -> Vertex {
+            let z = base + relief * f32::from(img.get_pixel(i as u32, j as u32)[0]);
+            [i as f32 * step, (h - 1 - j) as f32 * step, z]
+        };
+        let bottom =
+            |i: usize, j: usize| -> Vertex { [i as f32 * step, (h - 1 - j) as f32 * step, 0.0] };
+
+        // Boundary, counter-clockwise seen from above
+        let mut boundary: Vec<(usize, usize)> = Vec::with_capacity(2 * (w + h));
+        boundary.extend((0..w - 1).map(|i| (i, h - 1)));
+        boundary.extend((1..h).rev().map(|j| (w - 1, j)));
+        boundary.extend((1..w).rev().map(|i| (i, 0)));
+        boundary.extend((0..h - 1).map(|j| (0, j)));
+
+        let mut stl = StlWriter::with_capacity(2 * (w - 1) * (h - 1) + 3 * boundary.len());
+
+        for j in 0..h - 1 {
+            for i in 0..w - 1 {
+                let a = top(i, j);
+                let b = top(i + 1, j);
+                let c = top(i, j + 1);
+                let d = top(i + 1, j + 1);
+                stl.triangle(a, c, b);
+                stl.triangle(b, c, d);
+            }
+        }
+
+        let center = [
+            (w - 1) as f32 * step / 2.0,
+            (h - 1) as f32 * step / 2.0,
+            0.0,
+        ];
+        for (k, &(i, j)) in boundary.iter().enumerate() {
+            let (ni, nj) = boundary[(k + 1) % boundary.len()];
+
+            // Side wall
+            stl.triangle(bottom(i, j), bottom(ni, nj), top(ni, nj));
+            stl.triangle(bottom(i, j), top(ni, nj), top(i, j));
+
+            // Base (fan around the center, facing downwards)
+            stl.triangle(center, bottom(ni, nj), bottom(i, j));
+        }
+
+        stl.finish()
+    }
+}
+
+impl MeshBackend for NativeBackend {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn mesh(
+        &mut self,
+        heightmap: &[u8],
+        _settings: Option<&RequestSettings>,
+        _control: &JobControl,
+    ) -> Result<MeshOutput> {
+        let start = Instant::now();
+
+        let img = self.load_heightmap(heightmap)?;
+        let model = self.build(&img);
+
+        Ok(MeshOutput {
+            model,
+            metadata: MeshMetadata {
+                backend: self.name(),
+                duration: start.elapsed(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use image::Luma;
+
+    use super::*;
+
+    fn backend() -> NativeBackend {
+        NativeBackend::create(&NativeMesherSettings {
+            size_mm: 10.0,
+            relief_mm: 2.0,
+            base_mm: 1.0,
+            max_resolution: 400,
+        })
+        .unwrap()
+    }
+
+    fn heightmap(width: u32, height: u32, pixels: &[u8]) -> GrayImage {
+        GrayImage::from_fn(width, height, |x, y| {
+            Luma([pixels[(y * width + x) as usize]])
+        })
+    }
+
+    fn read_vertex(data: &[u8]) -> Vertex {
+        let mut v = [0.0; 3];
+        for (k, c) in v.iter_mut().enumerate() {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&data[4 * k..4 * k + 4]);
+            *c = f32::from_le_bytes(bytes);
+        }
+        v
+    }
+
+    /// Triangles of a binary STL file
+    fn triangles(stl: &[u8]) -> Vec<[Vertex; 3]> {
+        let mut count = [0; 4];
+        count.copy_from_slice(&stl[80..84]);
+        let count = u32::from_le_bytes(count) as usize;
+        assert_eq!(stl.len(), 84 + 50 * count);
+
+        stl[84..]
+            .chunks(50)
+            .map(|t| {
+                [
+                    read_vertex(&t[12..24]),
+                    read_vertex(&t[24..36]),
+                    read_vertex(&t[36..48]),
+                ]
+            })
+            .collect()
+    }
+
+    fn key(v: Vertex) -> [u32; 3] {
+        [v[0].to_bits(), v[1].to_bits(), v[2].to_bits()]
+    }
+
+    /// Every edge is shared by exactly two triangles, which traverse it in opposite directions
+    fn assert_watertight(triangles: &[[Vertex; 3]]) {
+        let mut edges: HashMap<([u32; 3], [u32; 3]), usize> = HashMap::new();
+        for t in triangles {
+            for k in 0..3 {
+                *edges.entry((key(t[k]), key(t[(k + 1) % 3]))).or_default() += 1;
+            }
+        }
+        for (&(a, b), &count) in &edges {
+            assert_eq!(count, 1, "edge {:?} -> {:?} used {} times", a, b, count);
+            assert_eq!(
+                edges.get(&(b, a)),
+                Some(&1),
+                "edge {:?} -> {:?} is not shared",
+                a,
+                b
+            );
+        }
+    }
+
+    fn z_range(triangles: &[[Vertex; 3]]) -> (f32, f32) {
+        triangles
+            .iter()
+            .flatten()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| {
+                (min.min(v[2]), max.max(v[2]))
+            })
+    }
+
+    /// Positive if the triangles face outwards
+    fn volume(triangles: &[[Vertex; 3]]) -> f32 {
+        triangles
+            .iter()
+            .map(|[a, b, c]| {
+                (a[0] * (b[1] * c[2] - b[2] * c[1]) - a[1] * (b[0] * c[2] - b[2] * c[0])
+                    + a[2] * (b[0] * c[1] - b[1] * c[0]))
+                    / 6.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn meshes_flat_heightmap() {
+        let triangles = triangles(&backend().build(&heightmap(2, 2, &[0; 4])));
+
+        // 2 on top, 2 per side wall and 1 per base segment for the 4 boundary edges
+        assert_eq!(triangles.len(), 2 + 3 * 4);
+        assert_watertight(&triangles);
+        assert_eq!(z_range(&triangles), (0.0, 1.0));
+        assert!((volume(&triangles) - 10.0 * 10.0 * 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn meshes_relief() {
+        #[rustfmt::skip]
+        let pixels = [
+            0, 64, 0,
+            128, 255, 128,
+            0, 64, 0,
+        ];
+        let triangles = triangles(&backend().build(&heightmap(3, 3, &pixels)));
+
+        assert_eq!(triangles.len(), 2 * 2 * 2 + 3 * 8);
+        assert_watertight(&triangles);
+        // Black pixels are at the top of the base, white ones `relief_mm` above
+        assert_eq!(z_range(&triangles), (0.0, 1.0 + 2.0));
+        assert!(volume(&triangles) > 10.0 * 10.0 * 1.0);
+    }
+
+    #[test]
+    fn meshes_non_square_heightmap() {
+        let pixels = [255, 0, 255, 0, 255, 0];
+        let triangles = triangles(&backend().build(&heightmap(3, 2, &pixels)));
+
+        assert_eq!(triangles.len(), 2 * 2 + 3 * 6);
+        assert_watertight(&triangles);
+        assert_eq!(z_range(&triangles), (0.0, 3.0));
+        let (min_y, max_y) = triangles
+            .iter()
+            .flatten()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| {
+                (min.min(v[1]), max.max(v[1]))
+            });
+        // The longer side is `size_mm` long
+        assert_eq!((min_y, max_y), (0.0, 5.0));
+    }
+}
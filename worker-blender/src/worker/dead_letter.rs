@@ -0,0 +1,118 @@
+use std::convert::TryInto;
+
+use log::{info, warn};
+
+use fabseal_micro_common::*;
+
+use redis::{
+    streams::{StreamId, StreamMaxlen, StreamRangeReply},
+    Commands, RedisResult, Value,
+};
+
+/// Stream fields that are copied between the submission and the dead-letter stream
+const JOB_FIELDS: [&str; 2] = ["request_id", "settings"];
+
+fn job_fields(msg: &StreamId) -> Vec<(&'static str, Vec<u8>)> {
+    JOB_FIELDS
+        .iter()
+        .filter_map(|&field| match msg.map.get(field) {
+            Some(Value::Data(v)) => Some((field, v.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Moves a failed job to the dead-letter stream
+///
+/// The entry holds the original job fields together with the error message and the number of attempts.
+pub(crate) fn add(
+    conn: &mut redis::Connection,
+    msg: &StreamId,
+    error: &str,
+    attempts: u32,
+    limit: usize,
+) -> RedisResult<()> {
+    let mut items = job_fields(msg);
+    items.push(("error", error.as_bytes().to_vec()));
+    items.push(("attempts", attempts.to_string().into_bytes()));
+    items.push(("failed_at", unix_timestamp().to_string().into_bytes()));
+    items.push(("source_id", msg.id.as_bytes().to_vec()));
+
+    let entry_id: String = conn.xadd_maxlen(
+        FABSEAL_SUBMISSION_DEAD_LETTER_QUEUE,
+        StreamMaxlen::Approx(limit),
+        "*",
+        &items,
+    )?;
+    info!("moved message {} to dead-letter entry {}", msg.id, entry_id);
+
+    Ok(())
+}
+
+/// Moves jobs from the dead-letter stream back to the submission stream
+///
+/// Requeues the given entries, or all entries if `ids` is empty.
+/// Returns the number of requeued jobs.
+pub(crate) fn requeue(
+    conn: &mut redis::Connection,
+    ids: &[String],
+    status_ttl: u32,
+) -> RedisResult<usize> {
+    let entries: Vec<StreamId> = if ids.is_empty() {
+        let reply: StreamRangeReply = conn.xrange_all(FABSEAL_SUBMISSION_DEAD_LETTER_QUEUE)?;
+        reply.ids
+    } else {
+        let mut entries = Vec::with_capacity(ids.len());
+        for id in ids {
+            let mut reply: StreamRangeReply =
+                conn.xrange_count(FABSEAL_SUBMISSION_DEAD_LETTER_QUEUE, id, id, 1)?;
+            match reply.ids.pop() {
+                Some(entry) => entries.push(entry),
+                None => warn!("dead-letter entry {} does not exist", id),
+            }
+        }
+        entries
+    };
+
+    let mut requeued = 0;
+    for entry in entries {
+        let request_id = match entry.map.get("request_id") {
+            Some(Value::Data(v)) => RequestId::from_bytes(v).ok(),
+            _ => None,
+        };
+        let request_id = match request_id {
+            Some(request_id) => request_id,
+            None => {
+                warn!(
+                    "dead-letter entry {} has no valid request id, skipping",
+                    entry.id
+                );
+                continue;
+            }
+        };
+
+        let entry_id: String = conn.xadd(FABSEAL_SUBMISSION_QUEUE, "*", &job_fields(&entry))?;
+
+        let key = status_key(request_id);
+        let _: () = conn.hset_multiple(
+            &key,
+            &[
+                ("state", JobState::Queued.as_str().to_string()),
+                ("queued_at", unix_timestamp().to_string()),
+                ("entry_id", entry_id.clone()),
+            ],
+        )?;
+        let _: () = conn.hdel(&key, &["started_at", "finished_at", "error"])?;
+        let _: () = conn.expire(&key, status_ttl.try_into().unwrap())?;
+
+        let _: usize = conn.xdel(FABSEAL_SUBMISSION_DEAD_LETTER_QUEUE, &[&entry.id])?;
+
+        info!(
+            "requeued request {} (dead-letter entry {}) as {}",
+            request_id, entry.id, entry_id
+        );
+        requeued += 1;
+    }
+
+    Ok(requeued)
+}
@@ -0,0 +1,191 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, warn};
+
+use color_eyre::eyre::Result;
+
+use fabseal_micro_common::*;
+
+use redis::Commands;
+
+use crate::metrics;
+
+/// Fields of the heartbeat that change while the worker is running
+#[derive(Default)]
+struct WorkerStatus {
+    current_request: Option<RequestId>,
+    jobs_done: u64,
+    jobs_failed: u64,
+    /// Publish before the next interval has passed
+    changed: bool,
+}
+
+/// Publishes the heartbeat hash of a worker from a separate thread, so that it stays alive during long jobs
+pub(crate) struct Heartbeat {
+    status: Arc<Mutex<WorkerStatus>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Fields of the heartbeat that are fixed for the lifetime of the worker
+pub(crate) struct WorkerInfo {
+    pub(crate) consumer_name: String,
+    pub(crate) backend: &'static str,
+    pub(crate) backend_version: String,
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        warn!("gethostname failed: {}", std::io::Error::last_os_error());
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+impl Heartbeat {
+    pub(crate) fn start(client: &redis::Client, info: WorkerInfo) -> Result<Heartbeat> {
+        let mut conn = client.get_connection()?;
+
+        let consumer_name = info.consumer_name.clone();
+        let key = worker_key(&consumer_name);
+        let fixed_fields: Vec<(&str, String)> = vec![
+            ("consumer", info.consumer_name.clone()),
+            ("host", hostname()),
+            ("pid", std::process::id().to_string()),
+            ("started_at", unix_timestamp().to_string()),
+            ("backend", info.backend.to_string()),
+            ("backend_version", info.backend_version),
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+        ];
+
+        let status = Arc::new(Mutex::new(WorkerStatus::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let status = Arc::clone(&status);
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name(format!("heartbeat-{}", info.consumer_name))
+                .spawn(move || {
+                    let interval = Duration::from_secs(WORKER_HEARTBEAT_INTERVAL_SECONDS);
+                    let mut next_publish = Instant::now();
+                    while !stop.load(Ordering::Relaxed) {
+                        let fields = {
+                            let mut status = status.lock().unwrap();
+                            let due = Instant::now() >= next_publish;
+                            if due || status.changed {
+                                status.changed = false;
+                                Some(Self::status_fields(&status))
+                            } else {
+                                None
+                            }
+                        };
+                        if let Some(fields) = fields {
+                            next_publish = Instant::now() + interval;
+                            if let Err(e) =
+                                Self::publish(&mut conn, &consumer_name, &fixed_fields, &fields)
+                            {
+                                error!("error while publishing heartbeat: {}", e);
+                                metrics::REDIS_ERRORS
+                                    .with_label_values(&["heartbeat"])
+                                    .inc();
+                            }
+                        }
+                        thread::sleep(Duration::from_millis(200));
+                    }
+
+                    let res: redis::RedisResult<()> = conn
+                        .del(&key)
+                        .and_then(|()| conn.srem(worker_registry_key(), &consumer_name));
+                    if let Err(e) = res {
+                        warn!("error while removing heartbeat: {}", e);
+                    }
+                    debug!("heartbeat stopped");
+                })?
+        };
+
+        Ok(Heartbeat {
+            status,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    fn status_fields(status: &WorkerStatus) -> Vec<(&'static str, String)> {
+        vec![
+            (
+                "current_request_id",
+                status
+                    .current_request
+                    .map(|id| id.to_string())
+                    .unwrap_or_default(),
+            ),
+            ("jobs_done", status.jobs_done.to_string()),
+            ("jobs_failed", status.jobs_failed.to_string()),
+            ("last_seen", unix_timestamp().to_string()),
+        ]
+    }
+
+    fn publish(
+        conn: &mut redis::Connection,
+        consumer_name: &str,
+        fixed_fields: &[(&str, String)],
+        fields: &[(&str, String)],
+    ) -> redis::RedisResult<()> {
+        let key = worker_key(consumer_name);
+        redis::pipe()
+            .atomic()
+            .hset_multiple(&key, fixed_fields)
+            .ignore()
+            .hset_multiple(&key, fields)
+            .ignore()
+            .expire(&key, WORKER_HEARTBEAT_TTL_SECONDS as usize)
+            .ignore()
+            .sadd(worker_registry_key(), consumer_name)
+            .ignore()
+            .query(conn)
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut WorkerStatus),
+    {
+        let mut status = self.status.lock().unwrap();
+        f(&mut status);
+        status.changed = true;
+    }
+
+    pub(crate) fn set_current_request(&self, request_id: Option<RequestId>) {
+        self.update(|status| status.current_request = request_id);
+    }
+
+    pub(crate) fn job_done(&self) {
+        self.update(|status| status.jobs_done += 1);
+    }
+
+    pub(crate) fn job_failed(&self) {
+        self.update(|status| status.jobs_failed += 1);
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("heartbeat thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use image::{imageops, DynamicImage, ImageFormat, ImageOutputFormat};
+
+use log::debug;
+
+use color_eyre::eyre::Result;
+
+use fabseal_micro_common::RequestSettings;
+
+/// Downscaling factor for low quality requests
+const LOW_QUALITY_FACTOR: u32 = 2;
+
+/// Applies the user's model options to a processed heightmap (PNG)
+///
+/// The selected region has already been cut out by `fabseal-micro` while preparing the heightmap.
+pub(crate) fn apply_settings(heightmap: &[u8], settings: &RequestSettings) -> Result<Vec<u8>> {
+    let mut img = image::load_from_memory_with_format(heightmap, ImageFormat::Png)?.to_luma8();
+
+    if settings.is_inverted {
+        debug!("inverting heightmap");
+        imageops::invert(&mut img);
+    }
+
+    if settings.is_low_quality {
+        let (width, height) = img.dimensions();
+        let (width, height) = (
+            (width / LOW_QUALITY_FACTOR).max(1),
+            (height / LOW_QUALITY_FACTOR).max(1),
+        );
+        debug!("downscaling heightmap to {}x{}", width, height);
+        img = imageops::resize(&img, width, height, imageops::FilterType::Triangle);
+    }
+
+    let mut out = Vec::new();
+    DynamicImage::ImageLuma8(img).write_to(&mut out, ImageOutputFormat::Png)?;
+    Ok(out)
+}